@@ -0,0 +1,200 @@
+use std::fmt;
+
+use crate::sync::*;
+#[cfg(feature = "borrow-tracking")]
+use std::{backtrace::Backtrace, cell::{Cell, RefCell}, collections::HashMap, panic::Location, rc::Rc};
+
+// Why a try_get_ref/try_get_ref_mut failed; each variant carries the full type name of what was borrowed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BorrowError {
+    // The element or entity was destroyed, or there is no such resource
+    Dead(&'static str),
+    // A mutable reference is alive
+    AlreadyMutablyBorrowed(&'static str),
+    // Immutable references are alive, so no mutable one can be made
+    AlreadyBorrowed(&'static str)
+}
+
+impl BorrowError {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            BorrowError::Dead(name) => name,
+            BorrowError::AlreadyMutablyBorrowed(name) => name,
+            BorrowError::AlreadyBorrowed(name) => name
+        }
+    }
+}
+
+impl fmt::Display for BorrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BorrowError::Dead(name) => write!(f, "Reference to \"{}\" attempted to be created from a dead address", name),
+            BorrowError::AlreadyMutablyBorrowed(name) => write!(f, "Instance of \"{}\" is already borrowed mutably", name),
+            BorrowError::AlreadyBorrowed(name) => write!(f, "Instance of \"{}\" is already borrowed immutably", name)
+        }
+    }
+}
+
+impl std::error::Error for BorrowError { }
+
+fn check_borrow(count: i64, mutable: bool, type_name: &'static str) -> Result<(), BorrowError> {
+    match (count, mutable) {
+        (c, _) if c < 0 => Err(BorrowError::AlreadyMutablyBorrowed(type_name)),
+        (c, true) if c > 0 => Err(BorrowError::AlreadyBorrowed(type_name)),
+        _ => Ok(())
+    }
+}
+
+// The borrow count of an element or entity: > 0 while immutably borrowed, -1 while mutably borrowed
+// Atomic with the sync feature
+pub struct BorrowCount {
+    #[cfg(not(feature = "sync"))]
+    count: std::cell::Cell<i64>,
+    #[cfg(feature = "sync")]
+    count: std::sync::atomic::AtomicI64
+}
+
+impl BorrowCount {
+    pub(crate) fn new() -> Self {
+        Self { count: 0.into() }
+    }
+    #[cfg(not(feature = "sync"))]
+    pub fn get(&self) -> i64 {
+        self.count.get()
+    }
+    #[cfg(feature = "sync")]
+    pub fn get(&self) -> i64 {
+        self.count.load(std::sync::atomic::Ordering::Acquire)
+    }
+    // Takes a reference unless it conflicts with the ones alive
+    #[cfg(not(feature = "sync"))]
+    pub(crate) fn acquire(&self, mutable: bool, type_name: &'static str) -> Result<(), BorrowError> {
+        check_borrow(self.get(), mutable, type_name)?;
+        self.count.set(match mutable { true => -1, false => self.get() + 1 });
+        Ok(())
+    }
+    #[cfg(feature = "sync")]
+    pub(crate) fn acquire(&self, mutable: bool, type_name: &'static str) -> Result<(), BorrowError> {
+        use std::sync::atomic::Ordering;
+        let mut current = self.get();
+        loop {
+            check_borrow(current, mutable, type_name)?;
+            let next = match mutable { true => -1, false => current + 1 };
+            match self.count.compare_exchange_weak(current, next, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Ok(()),
+                Err(actual) => current = actual
+            }
+        }
+    }
+    // Gives a reference back and returns the count left
+    #[cfg(not(feature = "sync"))]
+    pub(crate) fn release(&self, mutable: bool) -> i64 {
+        self.count.set(self.get() + match mutable { true => 1, false => -1 });
+        self.get()
+    }
+    #[cfg(feature = "sync")]
+    pub(crate) fn release(&self, mutable: bool) -> i64 {
+        let delta = match mutable { true => 1, false => -1 };
+        self.count.fetch_add(delta, std::sync::atomic::Ordering::AcqRel) + delta
+    }
+}
+
+// Identifies an element or entity by the address of its borrow count
+pub(crate) fn borrow_key<T>(internal: &WeakShared<T>) -> usize {
+    internal.as_ptr() as *const () as usize
+}
+
+// Held by every live EleRef/EleRefMut/EntRef/EntRefMut
+// With the borrow-tracking feature it records where the reference was made until it's dropped, otherwise it's empty
+pub(crate) struct BorrowToken {
+    #[cfg(feature = "borrow-tracking")]
+    key: usize,
+    #[cfg(feature = "borrow-tracking")]
+    id: u64
+}
+
+impl BorrowToken {
+    #[track_caller]
+    #[allow(unused_variables)]
+    pub(crate) fn new(key: usize, type_name: &'static str, mutable: bool) -> Self {
+        #[cfg(feature = "borrow-tracking")]
+        {
+            let id = NEXT_ID.with(|next| { next.set(next.get() + 1); next.get() });
+            let record = BorrowRecord {
+                type_name,
+                mutable,
+                location: Location::caller(),
+                backtrace: Rc::new(Backtrace::capture()),
+                key
+            };
+            REGISTRY.with(|registry| registry.borrow_mut().entry(key).or_default().push((id, record)));
+            Self { key, id }
+        }
+        #[cfg(not(feature = "borrow-tracking"))]
+        Self { }
+    }
+}
+
+#[cfg(feature = "borrow-tracking")]
+impl Drop for BorrowToken {
+    fn drop(&mut self) {
+        let _ = REGISTRY.try_with(|registry| {
+            let mut registry = registry.borrow_mut();
+            if let Some(records) = registry.get_mut(&self.key) {
+                records.retain(|(id, _)| *id != self.id);
+                if records.is_empty() {
+                    registry.remove(&self.key);
+                }
+            }
+        });
+    }
+}
+
+// The live references to whatever key identifies, formatted to be appended to a borrow panic message
+#[allow(unused_variables)]
+pub(crate) fn describe_holders(key: usize) -> String {
+    #[cfg(feature = "borrow-tracking")]
+    {
+        REGISTRY.with(|registry| registry.borrow().get(&key)
+            .map_or(String::new(), |records| records.iter().map(|(_, record)| format!("\n  held by {}", record)).collect()))
+    }
+    #[cfg(not(feature = "borrow-tracking"))]
+    String::new()
+}
+
+// Where a live reference was made
+// backtrace is only captured when RUST_BACKTRACE or RUST_LIB_BACKTRACE is set
+#[cfg(feature = "borrow-tracking")]
+#[derive(Clone)]
+pub struct BorrowRecord {
+    pub type_name: &'static str,
+    pub mutable: bool,
+    pub location: &'static Location<'static>,
+    pub backtrace: Rc<Backtrace>,
+    pub(crate) key: usize
+}
+
+#[cfg(feature = "borrow-tracking")]
+impl fmt::Display for BorrowRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.mutable {
+            true => "mutable",
+            false => "immutable"
+        };
+        write!(f, "{} reference to \"{}\" made at {}", kind, self.type_name, self.location)
+    }
+}
+
+#[cfg(feature = "borrow-tracking")]
+thread_local! {
+    static REGISTRY: RefCell<HashMap<usize, Vec<(u64, BorrowRecord)>>> = RefCell::new(HashMap::new());
+    static NEXT_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+// Every live reference on this thread, across all Managers, oldest first
+#[cfg(feature = "borrow-tracking")]
+pub fn outstanding_borrows() -> Vec<BorrowRecord> {
+    let mut records = REGISTRY.with(|registry| registry.borrow().values().flatten().cloned().collect::<Vec<(u64, BorrowRecord)>>());
+    records.sort_by_key(|(id, _)| *id);
+    records.into_iter().map(|(_, record)| record).collect()
+}
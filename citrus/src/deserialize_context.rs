@@ -1,50 +1,196 @@
 use uuid::Uuid;
-use std::{collections::HashMap, sync::RwLock};
+use std::{cell::RefCell, collections::HashMap, marker::PhantomData, rc::Rc};
+use serde::{Deserialize, Serialize};
+use serde::de::{DeserializeSeed, Deserializer, Error};
 
+use crate::element::{EleAddr, EleAddrSerdeState, Element};
 use crate::entity::{EntAddr, Manager};
 
-// I'm too *lazy* to make a stateful deserializer so this static state must suffice
-// no unsafe tho
-lazy_static! {
-    static ref ID_MAP: RwLock<HashMap<Uuid, EntAddr>> = RwLock::new(HashMap::new());
-    static ref IN_DESERIALIZE: RwLock<bool> = RwLock::new(false);
+// Serialized form of an entity id: a full UUID string, or the truncated i64 written by scenes
+// from before the versioned scene format. A null address is serialized as None
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SerializedEntId {
+    Full(String),
+    Legacy(i64)
 }
 
-pub fn begin_deserialize() {
-    assert!(!*IN_DESERIALIZE.read().unwrap());
-    *IN_DESERIALIZE.write().unwrap() = true;
+impl SerializedEntId {
+    pub fn from_id(id: Uuid) -> Option<Self> {
+        match id.is_nil() {
+            true => None,
+            false => Some(SerializedEntId::Full(id.to_string()))
+        }
+    }
+    pub fn to_id(&self) -> Result<Uuid, String> {
+        match self {
+            SerializedEntId::Full(st) => Uuid::parse_str(st).map_err(|err| format!("Invalid entity id \"{}\": {}", st, err)),
+            SerializedEntId::Legacy(v) => Ok(Uuid::from_u128(*v as u128))
+        }
+    }
+}
+
+// Maps serialized entity ids to the entities created for them during one deserialization
+// Cloning shares the same mapping
+#[derive(Clone, Default)]
+pub struct DeserializeContext {
+    id_map: Rc<RefCell<HashMap<Uuid, EntAddr>>>
+}
+
+thread_local! {
+    // Contexts made current by DeserializeContext::scope, innermost last
+    static CURRENT: RefCell<Vec<DeserializeContext>> = const { RefCell::new(Vec::new()) };
 }
 
-pub fn end_deserialize() {
-    assert!(*IN_DESERIALIZE.read().unwrap());
-    *IN_DESERIALIZE.write().unwrap() = false;
-    *ID_MAP.write().unwrap() = HashMap::new();
+// Pops the scoped context even if deserialization panics
+struct ScopeGuard;
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.borrow_mut().pop());
+    }
 }
 
-pub fn map_id(id: Uuid) -> EntAddr {
-    assert!(*IN_DESERIALIZE.read().unwrap());
-    
-    match id.as_u128() == 0 {
-        true => EntAddr::new(),
-        false => {
-            match (*ID_MAP.write().unwrap()).entry(id) {
-                std::collections::hash_map::Entry::Occupied(existing) => existing.get().clone(),
-                std::collections::hash_map::Entry::Vacant(_) => EntAddr::new()
+impl DeserializeContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn map_id(&self, id: Uuid) -> EntAddr {
+        match id.as_u128() == 0 {
+            true => EntAddr::new(),
+            false => self.id_map.borrow().get(&id).cloned().unwrap_or_else(EntAddr::new)
+        }
+    }
+
+    pub fn set_mapping(&mut self, id_ser: Uuid, name: String, man: &mut Manager) -> EntAddr {
+        assert!(id_ser.as_u128() != 0);
+        assert!(!self.id_map.borrow().contains_key(&id_ser), "Entity id {} was deserialized twice", id_ser);
+
+        // Keep the saved id unless the Manager already has an entity with it
+        let res = match man.find_by_id(id_ser).valid() {
+            true => man.create_entity(name),
+            false => man.create_entity_with_id(name, id_ser).unwrap()
+        };
+
+        self.id_map.borrow_mut().insert(id_ser, res.clone());
+
+        res
+    }
+
+    // Like set_mapping, but the new entity always gets a fresh id so the same content can be loaded any number of times
+    pub fn set_mapping_fresh(&mut self, id_ser: Uuid, name: String, man: &mut Manager) -> EntAddr {
+        assert!(id_ser.as_u128() != 0);
+        assert!(!self.id_map.borrow().contains_key(&id_ser), "Entity id {} was deserialized twice", id_ser);
+
+        let res = man.create_entity(name);
+        self.id_map.borrow_mut().insert(id_ser, res.clone());
+
+        res
+    }
+
+    // Maps every entity id mentioned in payload to the entity of man that already has that id
+    // Used to deserialize element data in place, where ids refer to live entities rather than a scene being loaded
+    pub fn for_payload(man: &Manager, payload: &serde_json::Value) -> Self {
+        let mut res = Self::new();
+        res.map_live_ids(man, payload);
+        res
+    }
+
+    // Same as for_payload, but ids this context already maps are left alone
+    pub fn map_live_ids(&mut self, man: &Manager, payload: &serde_json::Value) {
+        fn collect_ids(val: &serde_json::Value, ids: &mut Vec<Uuid>) {
+            match val {
+                serde_json::Value::String(st) => ids.extend(Uuid::parse_str(st).ok()),
+                serde_json::Value::Array(arr) => arr.iter().for_each(|v| collect_ids(v, ids)),
+                serde_json::Value::Object(obj) => obj.values().for_each(|v| collect_ids(v, ids)),
+                _ => { }
             }
         }
+
+        let mut ids = Vec::new();
+        collect_ids(payload, &mut ids);
+
+        let mut id_map = self.id_map.borrow_mut();
+        for id in ids.into_iter() {
+            let ent = man.find_by_id(id);
+            if ent.valid() && !id_map.contains_key(&id) {
+                id_map.insert(id, ent);
+            }
+        }
+    }
+
+    // Makes this context current on this thread while f runs, so that the plain
+    // Deserialize impls of EntAddr and EleAddr (e.g. inside derived element impls) can use it
+    pub fn scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        CURRENT.with(|current| current.borrow_mut().push(self.clone()));
+        let _guard = ScopeGuard;
+        f()
+    }
+
+    // The innermost context made current by scope, if any
+    pub fn current() -> Option<DeserializeContext> {
+        CURRENT.with(|current| current.borrow().last().cloned())
+    }
+}
+
+fn no_context_error<E: Error>(type_name: &str) -> E {
+    E::custom(format!("{} can only be deserialized inside DeserializeContext::scope", type_name))
+}
+
+// Deserializes an EntAddr through an explicit context
+pub struct EntAddrSeed<'a>(pub &'a DeserializeContext);
+
+impl<'a, 'de> DeserializeSeed<'de> for EntAddrSeed<'a> {
+    type Value = EntAddr;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let v: Option<SerializedEntId> = Deserialize::deserialize(deserializer)?;
+
+        match v {
+            Some(v) => Ok(self.0.map_id(v.to_id().map_err(D::Error::custom)?)),
+            None => Ok(EntAddr::new())
+        }
     }
 }
 
-pub fn set_mapping(id_ser: Uuid, name: String, man: &mut Manager) -> EntAddr {
-    assert!(*IN_DESERIALIZE.read().unwrap());
-    assert!(id_ser.as_u128() != 0);
+// Deserializes an EleAddr<T> through an explicit context
+pub struct EleAddrSeed<'a, T: Element>(pub &'a DeserializeContext, pub PhantomData<T>);
 
-    assert!(!(*ID_MAP.write().unwrap()).contains_key(&id_ser));
+impl<'a, T: Element> EleAddrSeed<'a, T> {
+    pub fn new(context: &'a DeserializeContext) -> Self {
+        Self(context, PhantomData)
+    }
+}
+
+impl<'a, 'de, T: Element> DeserializeSeed<'de> for EleAddrSeed<'a, T> {
+    type Value = EleAddr<T>;
 
-    let res = man.create_entity(name);
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let v: EleAddrSerdeState = Deserialize::deserialize(deserializer)?;
 
-    // TODO: assert that it doesn't contain the value either
-    (*ID_MAP.write().unwrap()).insert(id_ser, res.clone());
+        let ent = match v.ent_id {
+            Some(v) => self.0.map_id(v.to_id().map_err(D::Error::custom)?),
+            None => EntAddr::new()
+        };
 
-    res
-}
\ No newline at end of file
+        match ent.valid() {
+            true => {
+                let mut ent_ref = ent.get_ref_mut().unwrap();
+                Ok(ent_ref.query_elements::<T>().into_iter().nth(v.instance).unwrap_or_else(EleAddr::new))
+            },
+            false => Ok(EleAddr::<T>::new())
+        }
+    }
+}
+
+// Used by the Deserialize impls of EntAddr and EleAddr
+pub(crate) fn deserialize_ent_addr<'de, D: Deserializer<'de>>(deserializer: D) -> Result<EntAddr, D::Error> {
+    let context = DeserializeContext::current().ok_or_else(|| no_context_error("EntAddr"))?;
+    EntAddrSeed(&context).deserialize(deserializer)
+}
+
+pub(crate) fn deserialize_ele_addr<'de, T: Element, D: Deserializer<'de>>(deserializer: D) -> Result<EleAddr<T>, D::Error> {
+    let context = DeserializeContext::current().ok_or_else(|| no_context_error(std::any::type_name::<EleAddr<T>>()))?;
+    EleAddrSeed::<T>::new(&context).deserialize(deserializer)
+}
@@ -1,20 +1,29 @@
 use std::ops::{Deref, DerefMut};
-use std::rc::{Rc, Weak};
-use std::cell::{Cell, RefCell};
+use std::cell::RefCell;
 use std::any::{Any, TypeId};
 use std::hash::Hash;
 
 use serde::Serialize;
 use serde::de::DeserializeOwned;
-use uuid::Uuid;
 
+use crate::borrow::*;
 use crate::deserialize_context::*;
 use crate::entity::*;
+use crate::handles::*;
+use crate::sync::*;
 
 // Utility functions
 fn static_dyn_ref_null() -> &'static mut dyn Element {
     unsafe { std::mem::transmute([0, 0, 0, 0]) }
 }
+#[track_caller]
+fn dead_to_none<R>(res: Result<R, BorrowError>, key: usize) -> Option<R> {
+    match res {
+        Ok(r) => Some(r),
+        Err(BorrowError::Dead(_)) => None,
+        Err(err) => panic!("{}{}", err, describe_holders(key))
+    }
+}
 fn static_dyn_ref_from_concrete<T: Element>(concrete: &mut T) -> &'static mut dyn Element {
     unsafe { std::mem::transmute(concrete as &mut dyn Element) }
 }
@@ -23,10 +32,48 @@ fn static_dyn_ref_from_concrete<T: Element>(concrete: &mut T) -> &'static mut dy
 pub trait ElementSerde : 'static {
     fn ecs_serialize(&self) -> serde_json::Value;
     fn ecs_deserialize(&mut self, _data: serde_json::Value) -> Result<(), serde_json::Error>;
+    // Adds a copy of this element to ent, used by Manager::duplicate_entity
+    // Elements that can't be copied return an invalid address and are left out of the duplicate
+    fn ecs_duplicate_into(&self, _ent: &mut Entity) -> EleAddrErased {
+        EleAddrErased::new()
+    }
 }
 
-pub trait Element : ElementSerde {
+pub trait Element : ElementSerde + MaybeSync {
+    // Types returning true can be added to one entity any number of times, see Entity::query_elements
+    fn multi_instance() -> bool where Self: Sized {
+        false
+    }
+    // Manager::update runs all elements of a lower priority before any element of a higher one
+    fn update_priority() -> i32 where Self: Sized {
+        0
+    }
+    // Element types whose elements have to be updated before any element of this type, e.g. vec![TypeId::of::<Transform>()]
+    fn update_after() -> Vec<TypeId> where Self: Sized {
+        Vec::new()
+    }
     fn update(&mut self, _man: &mut Manager, _owner: EntAddr) { }
+    // Runs before update, once per fixed step of Time; only when a fixed timestep is set
+    fn fixed_update(&mut self, _man: &mut Manager, _owner: EntAddr) { }
+    // Event types passed to on_event, e.g. vec![TypeId::of::<Damage>()]
+    fn event_types() -> Vec<TypeId> where Self: Sized {
+        Vec::new()
+    }
+    // Called at the start of Manager::update for each event of one of event_types sent during the previous update,
+    // if it was broadcast or sent to the owner; downcast event to read it
+    fn on_event(&mut self, _man: &mut Manager, _owner: EntAddr, _event: &dyn Any) { }
+
+    // Lifecycle hooks
+    // Called on the first Manager::resolve after the element was added to an entity of the Manager
+    fn on_added(&mut self, _man: &mut Manager, _owner: EntAddr) { }
+    // Called by Manager::resolve just before a destroyed element is removed
+    fn on_removed(&mut self, _man: &mut Manager, _owner: EntAddr) { }
+    // Called by Manager::resolve just before the owner, along with this element, is destroyed
+    fn on_owner_destroyed(&mut self, _man: &mut Manager, _owner: EntAddr) { }
+    // Called by Manager::reparent when the owner gets a new parent
+    fn on_parent_changed(&mut self, _man: &mut Manager, _owner: EntAddr, _old_parent: EntAddr) { }
+    // Called by SceneSerde once every element of a scene has been loaded, after on_added
+    fn on_deserialized(&mut self, _man: &mut Manager, _owner: EntAddr) { }
     #[cfg(feature = "gen-imgui")]
     fn fill_ui(&mut self, ui: &imgui::Ui, _man: &mut Manager) {
         ui.text("Unimplemented ui");
@@ -46,13 +93,83 @@ impl<T: Element + Sized + 'static + DeserializeOwned + Serialize + Clone> Elemen
             Err(err) => Err(err)
         }
     }
+    fn ecs_duplicate_into(&self, ent: &mut Entity) -> EleAddrErased {
+        ent.add_element(self.clone()).map_or(EleAddrErased::new(), |a| a.into())
+    }
+}
+
+// Shared by an ElementHolder and every address and ref to its element
+// borrows is > 0 while immutably borrowed and -1 while mutably borrowed
+// changed is set by writes through EleRefMut/EleRefErasedMut and cleared by Manager::clear_changes
+// slot is the key behind the element's EleHandle, once it's owned by a Manager's entity
+// index is where the element sits in the Manager's list of elements of its type
+// A disabled element is skipped by Manager::update, events and queries but otherwise stays usable
+pub struct ElementState {
+    borrows: BorrowCount,
+    changed: SharedCell<bool>,
+    slot: SharedCell<Option<SlotKey>>,
+    index: SharedCell<usize>,
+    enabled: SharedCell<bool>
+}
+
+impl ElementState {
+    // A new element counts as changed until the next clear
+    fn new() -> Self {
+        Self {
+            borrows: BorrowCount::new(),
+            changed: SharedCell::new(true),
+            slot: SharedCell::new(None),
+            index: SharedCell::new(0),
+            enabled: SharedCell::new(true)
+        }
+    }
+    pub fn get(&self) -> i64 {
+        self.borrows.get()
+    }
+    fn acquire(&self, mutable: bool, type_name: &'static str) -> Result<(), BorrowError> {
+        self.borrows.acquire(mutable, type_name)
+    }
+    fn release(&self, mutable: bool) -> i64 {
+        self.borrows.release(mutable)
+    }
+    pub fn is_changed(&self) -> bool {
+        self.changed.get()
+    }
+    pub fn mark_changed(&self) {
+        self.changed.set(true)
+    }
+    pub(crate) fn clear_changed(&self) {
+        self.changed.set(false)
+    }
+    pub(crate) fn slot(&self) -> Option<SlotKey> {
+        self.slot.get()
+    }
+    pub(crate) fn set_slot(&self, slot: Option<SlotKey>) {
+        self.slot.set(slot)
+    }
+    pub(crate) fn index(&self) -> usize {
+        self.index.get()
+    }
+    pub(crate) fn set_index(&self, index: usize) {
+        self.index.set(index)
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.set(enabled)
+    }
 }
 
 pub struct ElementHolder {
     data: Box<RefCell<dyn Any>>, // must be cleaned up with a Box::from_raw
     element_ptr: &'static mut dyn Element,
-    internal: Rc<Cell<i64>>,
+    internal: Shared<ElementState>,
     id: std::any::TypeId,
+    type_name: &'static str,
+    update_priority: i32,
+    update_after: Vec<TypeId>,
+    event_types: Vec<TypeId>,
     owner: EntAddr
 }
 
@@ -61,11 +178,15 @@ impl ElementHolder {
         let mut res = Self {
             data: Box::new(RefCell::new(val)),
             element_ptr: static_dyn_ref_null(), // value overwritten later, just ignore and don't use for now 
-            internal: Rc::new(Cell::new(0)),
+            internal: Shared::new(ElementState::new()),
             id: std::any::TypeId::of::<T>(),
+            type_name: std::any::type_name::<T>(),
+            update_priority: T::update_priority(),
+            update_after: T::update_after(),
+            event_types: T::event_types(),
             owner
         };
-        res.element_ptr = static_dyn_ref_from_concrete(res.make_addr::<T>().get_ref_mut().unwrap().deref_mut());
+        res.element_ptr = static_dyn_ref_from_concrete(res.make_addr::<T>().get_ref_mut().unwrap().untracked());
         res
     }
     pub fn get_ent(&self) -> EntAddr {
@@ -74,6 +195,24 @@ impl ElementHolder {
     pub fn get_element_type_id(&self) -> std::any::TypeId {
         self.id
     }
+    pub fn get_element_type_name(&self) -> &'static str {
+        self.type_name
+    }
+    pub fn get_update_priority(&self) -> i32 {
+        self.update_priority
+    }
+    pub fn get_update_after(&self) -> &[TypeId] {
+        &self.update_after
+    }
+    pub fn get_event_types(&self) -> &[TypeId] {
+        &self.event_types
+    }
+    pub fn handles_event(&self, id: TypeId) -> bool {
+        self.event_types.contains(&id)
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.internal.is_enabled()
+    }
     pub fn get_dyn_ref(&self) -> &dyn Element {
         self.element_ptr
     }
@@ -88,21 +227,31 @@ impl ElementHolder {
 
         EleAddr::<T> {
             data: c,
-            internal: Rc::downgrade(&self.internal),
+            internal: Shared::downgrade(&self.internal),
             owner: self.owner.clone(),
             init_state: None
         }
     }
+    pub(crate) fn holds(&self, internal: &WeakShared<ElementState>) -> bool {
+        std::ptr::eq(Shared::as_ptr(&self.internal), internal.as_ptr())
+    }
     pub fn make_addr_erased(&mut self) -> EleAddrErased {
         EleAddrErased {
             data: self.get_dyn_ref_mut(),
-            internal: Rc::downgrade(&self.internal),
+            internal: Shared::downgrade(&self.internal),
             id: self.id,
+            type_name: self.type_name,
             owner: self.owner.clone()
         }
     }
 }
 
+// Holds an Element, which is Send + Sync with the sync feature; the RefCell is never borrowed past make_addr
+#[cfg(feature = "sync")]
+unsafe impl Send for ElementHolder {}
+#[cfg(feature = "sync")]
+unsafe impl Sync for ElementHolder {}
+
 impl Drop for ElementHolder {
     fn drop(&mut self) {
         if std::thread::panicking() { return; }
@@ -111,19 +260,36 @@ impl Drop for ElementHolder {
     }
 }
 
+// instance tells apart elements of a multi_instance type on the same entity
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct EleAddrSerdeState {
-    ent_id: i64
+    pub(crate) ent_id: Option<SerializedEntId>,
+    #[serde(default, skip_serializing_if = "is_first_instance")]
+    pub(crate) instance: usize
+}
+
+fn is_first_instance(instance: &usize) -> bool {
+    *instance == 0
 }
 
 // Element Ref
 pub struct EleAddr<T: Element> {
     data: *mut T,
-    internal: Weak<Cell<i64>>,
+    internal: WeakShared<ElementState>,
     owner: EntAddr,
     init_state: Option<EleAddrSerdeState>
 }
 
+// With the sync feature elements are Send + Sync and the borrow counts atomic, which makes sharing the pointer sound
+#[cfg(feature = "sync")]
+unsafe impl<T: Element> Send for EleAddr<T> {}
+#[cfg(feature = "sync")]
+unsafe impl<T: Element> Sync for EleAddr<T> {}
+#[cfg(feature = "sync")]
+unsafe impl Send for EleAddrErased {}
+#[cfg(feature = "sync")]
+unsafe impl Sync for EleAddrErased {}
+
 impl<T: Element> Clone for EleAddr<T> {
     fn clone(&self) -> Self {
         Self {
@@ -139,12 +305,16 @@ impl<T: Element> serde::Serialize for EleAddr<T> {
     fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         let owner = self.get_owner();
         let id = match owner.get_ref() {
-            None => 0i64,
-            Some(e) => e.get_id().as_u128() as i64
+            None => None,
+            Some(e) => SerializedEntId::from_id(e.get_id())
         };
 
         (EleAddrSerdeState {
-            ent_id: id
+            ent_id: id,
+            instance: match T::multi_instance() {
+                true => self.instance_index().unwrap_or(0),
+                false => 0
+            }
         }).serialize(serializer)
     }
 }
@@ -153,17 +323,7 @@ impl<'de, T: Element> serde::Deserialize<'de> for EleAddr<T> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
     D: serde::Deserializer<'de> {
-        let v: EleAddrSerdeState = serde::Deserialize::deserialize(deserializer)?;
-
-        let ent = map_id(Uuid::from_u128(v.ent_id as u128));
-
-        match ent.valid() {
-            true => {
-                let mut ent_ref = ent.get_ref_mut().unwrap();
-                Ok(ent_ref.query_element_addr::<T>())
-            },
-            false => Ok(EleAddr::<T>::new())
-        }
+        deserialize_ele_addr(deserializer)
     }
 }
 
@@ -171,7 +331,7 @@ impl<T: Element> EleAddr<T> {
     pub fn new() -> Self {
         Self {
             data: std::ptr::null_mut(),
-            internal: Weak::new(),
+            internal: WeakShared::new(),
             owner: EntAddr::new(),
             init_state: None
         }
@@ -185,40 +345,79 @@ impl<T: Element> EleAddr<T> {
             false => EntAddr::new()
         }
     }
-    pub fn get_ref<'a>(&self) -> Option<EleRef<'a, T>> {
+    pub fn is_changed(&self) -> bool {
+        self.internal.upgrade().is_some_and(|rc| rc.is_changed())
+    }
+    // For changes the Manager can't see, e.g. an element changing itself in Element::update
+    pub fn mark_changed(&self) {
+        if let Some(rc) = self.internal.upgrade() {
+            rc.mark_changed();
+        }
+    }
+    // False for dead elements
+    pub fn is_enabled(&self) -> bool {
+        self.internal.upgrade().is_some_and(|rc| rc.is_enabled())
+    }
+    pub fn set_enabled(&self, enabled: bool) {
+        if let Some(rc) = self.internal.upgrade() {
+            rc.set_enabled(enabled);
+        }
+    }
+    // None for dead elements and ones not owned by a Manager's entity
+    pub fn handle(&self) -> Option<EleHandle<T>> {
+        Some(EleHandle::new(self.internal.upgrade()?.slot()?))
+    }
+    // Position among the elements of type T on the owner, in the order they were added
+    pub fn instance_index(&self) -> Option<usize> {
+        self.get_owner().get_ref()?.element_instance_index(TypeId::of::<T>(), &self.internal)
+    }
+    #[track_caller]
+    pub fn try_get_ref<'a>(&self) -> Result<EleRef<'a, T>, BorrowError> {
         match self.internal.upgrade() {
             Some(_) => {
                 let d = unsafe { &*self.data };
 
-                Some(EleRef::new(
+                EleRef::try_new(
                     unsafe { std::mem::transmute::<&T, &'a T>(d) }, // rewrite the lifetime
                     self.internal.clone()
-                ))
+                )
             },
-            None => None
+            None => Err(BorrowError::Dead(std::any::type_name::<T>()))
         }
     }
-    pub fn get_ref_mut<'a>(&mut self) -> Option<EleRefMut<'a, T>> {
+    #[track_caller]
+    pub fn try_get_ref_mut<'a>(&mut self) -> Result<EleRefMut<'a, T>, BorrowError> {
         match self.internal.upgrade() {
             Some(_) => {
                 let d = unsafe { &mut *self.data };
                 
-                Some(EleRefMut::new(
+                EleRefMut::try_new(
                     unsafe { std::mem::transmute::<&mut T, &'a mut T>(d) }, // rewrite the lifetime
                     self.internal.clone()
-                ))
+                )
             },
-            None => None
+            None => Err(BorrowError::Dead(std::any::type_name::<T>()))
         }
     }
+    // None if the element is dead, panics if it's already borrowed
+    #[track_caller]
+    pub fn get_ref<'a>(&self) -> Option<EleRef<'a, T>> {
+        dead_to_none(self.try_get_ref(), borrow_key(&self.internal))
+    }
+    #[track_caller]
+    pub fn get_ref_mut<'a>(&mut self) -> Option<EleRefMut<'a, T>> {
+        dead_to_none(self.try_get_ref_mut(), borrow_key(&self.internal))
+    }
 }
 pub struct EleRef<'a, T: Element> {
     data: &'a T,
-    internal: Weak<Cell<i64>>
+    internal: WeakShared<ElementState>,
+    _token: BorrowToken
 }
 pub struct EleRefMut<'a, T: Element> {
     pub data: &'a mut T,
-    pub internal: Weak<Cell<i64>>
+    pub internal: WeakShared<ElementState>,
+    _token: BorrowToken
 }
 
 impl<'a, T: Element> Drop for EleRef<'a, T> {
@@ -228,8 +427,7 @@ impl<'a, T: Element> Drop for EleRef<'a, T> {
             Some(rc) => rc,
             None => panic!("When dropping immutable reference of type \"{}\", the holder was already destroyed", std::any::type_name::<T>())
         };
-        rc.set(rc.get() - 1);
-        assert!(rc.get() >= 0, "Instance of Element \"{}\"'s ref count somehow dropped below zero", std::any::type_name::<T>());
+        assert!(rc.release(false) >= 0, "Instance of Element \"{}\"'s ref count somehow dropped below zero", std::any::type_name::<T>());
     }
 }
 impl<'a, T: Element> Drop for EleRefMut<'a, T> {
@@ -239,8 +437,7 @@ impl<'a, T: Element> Drop for EleRefMut<'a, T> {
             Some(rc) => rc,
             None => panic!("When dropping mutable reference of type \"{}\", the holder was already destroyed", std::any::type_name::<T>())
         };
-        rc.set(rc.get() + 1);
-        assert!(rc.get() == 0, "Instance of Element \"{}\"'s ref count didn't equal zero when dropping mutable reference", std::any::type_name::<T>());
+        assert!(rc.release(true) == 0, "Instance of Element \"{}\"'s ref count didn't equal zero when dropping mutable reference", std::any::type_name::<T>());
     }
 }
 impl<'a, T: Element> Deref for EleRef<'a, T> {
@@ -259,34 +456,39 @@ impl<'a, T: Element> Deref for EleRefMut<'a, T> {
 }
 impl<'a, T: Element> DerefMut for EleRefMut<'a, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
+        if let Some(rc) = self.internal.upgrade() {
+            rc.mark_changed();
+        }
         self.data
     }
 }
 impl<'a, T: Element> EleRef<'a, T> {
-    fn new(data: &'a T, internal: Weak<Cell<i64>>) -> Self {
-        if let Some(rc) = internal.upgrade() {
-            assert!(rc.get() >= 0, "Instance of Element \"{}\" is already borrowed mutably", std::any::type_name::<T>());
+    #[track_caller]
+    fn try_new(data: &'a T, internal: WeakShared<ElementState>) -> Result<Self, BorrowError> {
+        let rc = internal.upgrade().ok_or(BorrowError::Dead(std::any::type_name::<T>()))?;
+        rc.acquire(false, std::any::type_name::<T>())?;
 
-            rc.set(rc.get() + 1);
-        } else {
-            panic!("Immutable Reference to element \"{}\" attempted to be created from a dead address", std::any::type_name::<T>());
-        }
-
-        Self { data, internal }
+        let _token = BorrowToken::new(borrow_key(&internal), std::any::type_name::<T>(), false);
+        Ok(Self { data, internal, _token })
     }
 }
 impl<'a, T: Element> EleRefMut<'a, T> {
-    pub fn new(data: &'a mut T, internal: Weak<Cell<i64>>) -> Self {
-        if let Some(rc) = internal.upgrade() {
-            assert!(rc.get() <= 0, "Instance of Element \"{}\" is already borrowed immutably", std::any::type_name::<T>());
-            assert!(rc.get() >= 0, "Instance of Element \"{}\" is already borrowed mutably",   std::any::type_name::<T>());
-
-            rc.set(rc.get() - 1);
-        } else {
-            panic!("Mutable Reference to element \"{}\" attempted to be created from a dead address", std::any::type_name::<T>());
-        }
+    #[track_caller]
+    pub fn try_new(data: &'a mut T, internal: WeakShared<ElementState>) -> Result<Self, BorrowError> {
+        let rc = internal.upgrade().ok_or(BorrowError::Dead(std::any::type_name::<T>()))?;
+        rc.acquire(true, std::any::type_name::<T>())?;
 
-        Self { data, internal }
+        let _token = BorrowToken::new(borrow_key(&internal), std::any::type_name::<T>(), true);
+        Ok(Self { data, internal, _token })
+    }
+    #[track_caller]
+    pub fn new(data: &'a mut T, internal: WeakShared<ElementState>) -> Self {
+        let key = borrow_key(&internal);
+        Self::try_new(data, internal).unwrap_or_else(|err| panic!("{}{}", err, describe_holders(key)))
+    }
+    // Mutable access that doesn't count as a change, for calls the Manager makes itself
+    pub(crate) fn untracked(&mut self) -> &mut T {
+        self.data
     }
 }
 
@@ -294,8 +496,9 @@ impl<'a, T: Element> EleRefMut<'a, T> {
 #[derive(Clone)]
 pub struct EleAddrErased {
     data: *mut dyn Element,
-    internal: Weak<Cell<i64>>,
+    internal: WeakShared<ElementState>,
     id: std::any::TypeId,
+    type_name: &'static str,
     owner: EntAddr
 }
 
@@ -303,59 +506,142 @@ impl EleAddrErased {
     pub fn new() -> Self {
         Self {
             data: unsafe { std::mem::transmute([0, 0, 0, 0]) },
-            internal: Weak::new(),
+            internal: WeakShared::new(),
             id: std::any::TypeId::of::<()>(),
+            type_name: std::any::type_name::<()>(),
             owner: EntAddr::new()
         }
     }
     pub fn valid(&self) -> bool {
         self.internal.strong_count() > 0
     }
-    pub fn get_ref<'a>(&self) -> Option<EleRefErased<'a>> {
+    #[track_caller]
+    pub fn try_get_ref<'a>(&self) -> Result<EleRefErased<'a>, BorrowError> {
         match self.internal.upgrade() {
             Some(_) => {
                 let d = unsafe { &*self.data };
 
-                Some(EleRefErased::new(
+                EleRefErased::try_new(
                     unsafe { std::mem::transmute::<&dyn Element, &'a dyn Element>(d) }, // rewrite the lifetime
-                    self.internal.clone()
-                ))
+                    self.internal.clone(),
+                    self.type_name
+                )
             },
-            None => None
+            None => Err(BorrowError::Dead(self.type_name))
         }
     }
-    pub fn get_ref_mut<'a>(&mut self) -> Option<EleRefErasedMut<'a>> {
+    #[track_caller]
+    pub fn try_get_ref_mut<'a>(&mut self) -> Result<EleRefErasedMut<'a>, BorrowError> {
         match self.internal.upgrade() {
             Some(_) => {
                 let d = unsafe { &mut *self.data };
                 
-                Some(EleRefErasedMut::new(
+                EleRefErasedMut::try_new(
                     unsafe { std::mem::transmute::<&mut dyn Element, &'a mut dyn Element>(d) }, // rewrite the lifetime
-                    self.internal.clone()
-                ))
+                    self.internal.clone(),
+                    self.type_name
+                )
             },
-            None => None
+            None => Err(BorrowError::Dead(self.type_name))
         }
     }
+    // None if the element is dead, panics if it's already borrowed
+    #[track_caller]
+    pub fn get_ref<'a>(&self) -> Option<EleRefErased<'a>> {
+        dead_to_none(self.try_get_ref(), borrow_key(&self.internal))
+    }
+    #[track_caller]
+    pub fn get_ref_mut<'a>(&mut self) -> Option<EleRefErasedMut<'a>> {
+        dead_to_none(self.try_get_ref_mut(), borrow_key(&self.internal))
+    }
     pub fn get_owner(&self) -> EntAddr {
         self.owner.clone()
     }
+    pub(crate) fn is_borrowed(&self) -> bool {
+        self.internal.upgrade().is_some_and(|rc| rc.get() != 0)
+    }
+    pub(crate) fn slot(&self) -> Option<SlotKey> {
+        self.internal.upgrade()?.slot()
+    }
+    pub(crate) fn set_slot(&self, slot: Option<SlotKey>) {
+        if let Some(rc) = self.internal.upgrade() {
+            rc.set_slot(slot);
+        }
+    }
+    pub(crate) fn index(&self) -> Option<usize> {
+        Some(self.internal.upgrade()?.index())
+    }
+    pub(crate) fn set_index(&self, index: usize) {
+        if let Some(rc) = self.internal.upgrade() {
+            rc.set_index(index);
+        }
+    }
+    #[cfg(feature = "borrow-tracking")]
+    pub(crate) fn tracking_key(&self) -> usize {
+        borrow_key(&self.internal)
+    }
+    pub fn is_changed(&self) -> bool {
+        self.internal.upgrade().is_some_and(|rc| rc.is_changed())
+    }
+    pub fn mark_changed(&self) {
+        if let Some(rc) = self.internal.upgrade() {
+            rc.mark_changed();
+        }
+    }
+    pub(crate) fn clear_changed(&self) {
+        if let Some(rc) = self.internal.upgrade() {
+            rc.clear_changed();
+        }
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.internal.upgrade().is_some_and(|rc| rc.is_enabled())
+    }
+    pub fn set_enabled(&self, enabled: bool) {
+        if let Some(rc) = self.internal.upgrade() {
+            rc.set_enabled(enabled);
+        }
+    }
+    // Position among the elements of the same type on the owner, in the order they were added
+    pub fn instance_index(&self) -> Option<usize> {
+        self.get_owner().get_ref()?.element_instance_index(self.get_element_type_id()?, &self.internal)
+    }
     pub fn get_element_type_id(&self) -> Option<TypeId> {
         match self.valid() {
             false => None,
             true => Some(self.id)
         }
     }
+    // Full path of the element's type, as given by std::any::type_name
+    pub fn get_element_type_name(&self) -> Option<&'static str> {
+        match self.valid() {
+            false => None,
+            true => Some(self.type_name)
+        }
+    }
+    // Recovers the typed address; invalid if the element isn't of type T
+    pub fn downcast<T: Element>(&self) -> EleAddr<T> {
+        match self.get_element_type_id() == Some(TypeId::of::<T>()) {
+            true => EleAddr::<T> {
+                data: self.data as *mut T,
+                internal: self.internal.clone(),
+                owner: self.owner.clone(),
+                init_state: None
+            },
+            false => EleAddr::new()
+        }
+    }
 }
 
 /// EleRefErased
 pub struct EleRefErased<'a> {
     data: &'a dyn Element,
-    internal: Weak<Cell<i64>>
+    internal: WeakShared<ElementState>,
+    _token: BorrowToken
 }
 pub struct EleRefErasedMut<'a> {
     pub data: &'a mut dyn Element,
-    pub internal: Weak<Cell<i64>>
+    pub internal: WeakShared<ElementState>,
+    _token: BorrowToken
 }
 
 impl<'a> Drop for EleRefErased<'a> {
@@ -365,8 +651,7 @@ impl<'a> Drop for EleRefErased<'a> {
             Some(rc) => rc,
             None => panic!("When dropping immutable reference of type Compononet Erased, the holder was already destroyed")
         };
-        rc.set(rc.get() - 1);
-        assert!(rc.get() >= 0, "Instance of Element Erased's ref count somehow dropped below zero");
+        assert!(rc.release(false) >= 0, "Instance of Element Erased's ref count somehow dropped below zero");
     }
 }
 impl<'a> Drop for EleRefErasedMut<'a> {
@@ -376,8 +661,7 @@ impl<'a> Drop for EleRefErasedMut<'a> {
             Some(rc) => rc,
             None => panic!("When dropping mutable reference of type Element Erased, the holder was already destroyed")
         };
-        rc.set(rc.get() + 1);
-        assert!(rc.get() == 0, "Instance of Element Erased's ref count didn't equal zero when dropping mutable reference");
+        assert!(rc.release(true) == 0, "Instance of Element Erased's ref count didn't equal zero when dropping mutable reference");
     }
 }
 impl<'a> Deref for EleRefErased<'a> {
@@ -396,34 +680,38 @@ impl<'a> Deref for EleRefErasedMut<'a> {
 }
 impl<'a> DerefMut for EleRefErasedMut<'a> {
     fn deref_mut(&mut self) -> &mut Self::Target {
+        if let Some(rc) = self.internal.upgrade() {
+            rc.mark_changed();
+        }
         self.data
     }
 }
 impl<'a> EleRefErased<'a> {
-    fn new(data: &'a dyn Element, internal: Weak<Cell<i64>>) -> Self {
-        if let Some(rc) = internal.upgrade() {
-            assert!(rc.get() >= 0, "Instance of Element is already borrowed mutably");
+    #[track_caller]
+    fn try_new(data: &'a dyn Element, internal: WeakShared<ElementState>, type_name: &'static str) -> Result<Self, BorrowError> {
+        let rc = internal.upgrade().ok_or(BorrowError::Dead(type_name))?;
+        rc.acquire(false, type_name)?;
 
-            rc.set(rc.get() + 1);
-        } else {
-            panic!("Immutable Reference to Element Erased attempted to be created from a dead address");
-        }
-
-        Self { data, internal }
+        let _token = BorrowToken::new(borrow_key(&internal), type_name, false);
+        Ok(Self { data, internal, _token })
     }
 }
 impl<'a> EleRefErasedMut<'a> {
-    pub fn new(data: &'a mut dyn Element, internal: Weak<Cell<i64>>) -> Self {
-        if let Some(rc) = internal.upgrade() {
-            assert!(rc.get() <= 0, "Instance of Element Erased is already borrowed immutably");
-            assert!(rc.get() >= 0, "Instance of Element Erased is already borrowed mutably");
-
-            rc.set(rc.get() - 1);
-        } else {
-            panic!("Mutable Reference to Element Erased attempted to be created from a dead address");
-        }
+    #[track_caller]
+    pub fn try_new(data: &'a mut dyn Element, internal: WeakShared<ElementState>, type_name: &'static str) -> Result<Self, BorrowError> {
+        let rc = internal.upgrade().ok_or(BorrowError::Dead(type_name))?;
+        rc.acquire(true, type_name)?;
 
-        Self { data, internal }
+        let _token = BorrowToken::new(borrow_key(&internal), type_name, true);
+        Ok(Self { data, internal, _token })
+    }
+    #[track_caller]
+    pub fn new(data: &'a mut dyn Element, internal: WeakShared<ElementState>) -> Self {
+        let key = borrow_key(&internal);
+        Self::try_new(data, internal, "Element Erased").unwrap_or_else(|err| panic!("{}{}", err, describe_holders(key)))
+    }
+    pub(crate) fn untracked(&mut self) -> &mut dyn Element {
+        self.data
     }
 }
 
@@ -433,9 +721,10 @@ impl<T: Element> From<EleAddr<T>> for EleAddrErased {
             true => {
                 let mut other_mut = other.clone();
                 EleAddrErased {
-                    data: static_dyn_ref_from_concrete(other_mut.get_ref_mut().unwrap().deref_mut()),
+                    data: static_dyn_ref_from_concrete(other_mut.get_ref_mut().unwrap().untracked()),
                     internal: other.internal.clone(),
                     id: std::any::TypeId::of::<T>(),
+                    type_name: std::any::type_name::<T>(),
                     owner: other.owner.clone()
                 }
             },
@@ -1,22 +1,202 @@
-use std::{any::TypeId, cell::Cell, ops::{Deref, DerefMut}, rc::{Rc, Weak}};
+use std::{any::TypeId, ops::{Deref, DerefMut}};
 use std::hash::Hash;
-use std::{collections::HashSet};
+use std::{collections::{hash_map::Entry, HashMap, HashSet}};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::borrow::*;
 use crate::deserialize_context::*;
 use crate::element::*;
+use crate::events::*;
+use crate::handles::*;
+use crate::hierarchy::*;
+use crate::query::*;
+use crate::resources::*;
+use crate::schedule::*;
+use crate::sync::*;
+use crate::time::*;
 
 #[derive(Debug)]
 pub struct EntReferenceCycleError;
 
+// New entities are in layer 0 only
+pub const DEFAULT_LAYERS: u32 = 1;
+
+// Every element owned by a Manager's entities, grouped by type so type queries cost O(matches)
+// Shared between the Manager and its entities, which keep it up to date as elements come and go
+// added and removed record what came and went since the last Manager::clear_changes
+// slots resolves EleHandles
+// by_type lists are unordered, each element keeps its index in its ElementState so removal is O(1)
+// handlers holds the element types handling each event type, in the order the types appeared
+// levels caches the update passes until an element type appears or disappears
+#[derive(Default)]
+struct ElementIndex {
+    by_type: HashMap<TypeId, Vec<EleAddrErased>>,
+    types: HashMap<TypeId, TypeInfo>,
+    handlers: HashMap<TypeId, Vec<TypeId>>,
+    levels: Option<HashMap<TypeId, i64>>,
+    cycle: Option<String>,
+    added: Vec<EleAddrErased>,
+    removed: Vec<(TypeId, Uuid)>,
+    slots: Slots<EleAddrErased>
+}
+
+// Where the elements of a type go among the update passes, and the events they handle
+struct TypeInfo {
+    name: &'static str,
+    priority: i32,
+    after: Vec<TypeId>,
+    events: Vec<TypeId>
+}
+
+impl ElementIndex {
+    fn insert(&mut self, addr: EleAddrErased, info: TypeInfo) {
+        let key = self.slots.insert(addr.clone());
+        addr.set_slot(Some(key));
+        self.added.push(addr.clone());
+        let id = addr.get_element_type_id().unwrap();
+        if let Entry::Vacant(entry) = self.types.entry(id) {
+            info.events.iter().for_each(|event| self.handlers.entry(*event).or_default().push(id));
+            entry.insert(info);
+            self.levels = None;
+        }
+        let addrs = self.by_type.entry(id).or_default();
+        addr.set_index(addrs.len());
+        addrs.push(addr);
+    }
+    fn remove(&mut self, addr: &EleAddrErased, owner: Uuid) {
+        if let Some(key) = addr.slot() {
+            self.slots.remove(key);
+            addr.set_slot(None);
+        }
+        if let Some(id) = addr.get_element_type_id() {
+            self.removed.push((id, owner));
+            if let Some(addrs) = self.by_type.get_mut(&id) {
+                if let Some(index) = addr.index().filter(|index| addrs.get(*index) == Some(addr)) {
+                    addrs.swap_remove(index);
+                    if let Some(moved) = addrs.get(index) {
+                        moved.set_index(index);
+                    }
+                }
+                if addrs.is_empty() {
+                    self.by_type.remove(&id);
+                    if let Some(info) = self.types.remove(&id) {
+                        for event in info.events.iter() {
+                            let handlers = self.handlers.get_mut(event).unwrap();
+                            handlers.retain(|handler| *handler != id);
+                            if handlers.is_empty() {
+                                self.handlers.remove(event);
+                            }
+                        }
+                    }
+                    self.levels = None;
+                }
+            }
+        }
+    }
+    fn clear_changes(&mut self) {
+        self.added.clear();
+        self.removed.clear();
+        self.by_type.values().flatten().for_each(|ele| ele.clear_changed());
+    }
+    fn of_type(&self, id: &TypeId) -> &[EleAddrErased] {
+        self.by_type.get(id).map_or(&[], |addrs| addrs.as_slice())
+    }
+    // The elements of the types handling the event, type by type
+    fn handlers(&self, event: &TypeId) -> Vec<EleAddrErased> {
+        self.handlers.get(event).map_or_else(Vec::new, |types| {
+            types.iter().flat_map(|id| self.of_type(id).iter().cloned()).collect()
+        })
+    }
+    // The pass each element type present is updated in: its update_priority, raised past the types it has to update after
+    // A cycle of update_after constraints is broken by ignoring those of its type first by name, and kept in cycle
+    fn update_levels(&mut self) -> HashMap<TypeId, i64> {
+        if let Some(levels) = &self.levels {
+            return levels.clone();
+        }
+
+        let mut ignored = Vec::<TypeId>::new();
+        let mut cycles = Vec::<String>::new();
+        let levels = loop {
+            if let Some(levels) = self.settle_levels(&ignored) {
+                break levels;
+            }
+            let mut cyclic = self.types.keys()
+            .filter(|id| !ignored.contains(id) && self.on_cycle(**id, &ignored))
+            .copied()
+            .collect::<Vec<TypeId>>();
+            cyclic.sort_by_key(|id| self.types[id].name);
+            let names = cyclic.iter().map(|id| self.types[id].name).collect::<Vec<&str>>();
+            cycles.push(format!("Element update_after constraints form a cycle among {}, ignoring those of {}", names.join(", "), names[0]));
+            ignored.push(cyclic[0]);
+        };
+
+        self.cycle = match cycles.is_empty() {
+            true => None,
+            false => Some(cycles.join("\n"))
+        };
+        self.levels = Some(levels.clone());
+        levels
+    }
+    // None if the levels keep rising, which only a cycle does
+    fn settle_levels(&self, ignored: &[TypeId]) -> Option<HashMap<TypeId, i64>> {
+        let mut levels = self.types.iter()
+        .map(|(id, rule)| (*id, rule.priority as i64))
+        .collect::<HashMap<TypeId, i64>>();
+
+        // Every round settles at least one more type, so needing more rounds than there are types means a cycle
+        for _ in 0..=self.types.len() {
+            let mut changed = false;
+            for (id, rule) in self.types.iter().filter(|(id, _)| !ignored.contains(id)) {
+                for dep in rule.after.iter() {
+                    let dep_level = match levels.get(dep) {
+                        Some(dep_level) => *dep_level,
+                        None => continue
+                    };
+                    if levels[id] <= dep_level {
+                        levels.insert(*id, dep_level + 1);
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                return Some(levels);
+            }
+        }
+        None
+    }
+    // Whether id can reach itself through the update_after of the types present
+    fn on_cycle(&self, id: TypeId, ignored: &[TypeId]) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = self.types[&id].after.clone();
+        while let Some(dep) = stack.pop() {
+            if dep == id {
+                return true;
+            }
+            if ignored.contains(&dep) || !seen.insert(dep) {
+                continue;
+            }
+            if let Some(rule) = self.types.get(&dep) {
+                stack.extend(rule.after.iter().copied());
+            }
+        }
+        false
+    }
+}
+
 pub struct Entity {
     elements: Vec<ElementHolder>,
+    element_index: WeakShared<SharedLock<ElementIndex>>,
+    added_queue: WeakShared<SharedLock<Vec<EleAddrErased>>>,
     self_addr: EntAddr,
     parent_addr: EntAddr,
     children_addrs: Vec<EntAddr>,
     id: Uuid,
+    handle: EntHandle,
+    active: bool,
+    tags: Vec<String>,
+    layers: u32,
     pub name: String,
 }
 
@@ -24,6 +204,62 @@ impl Entity {
     pub fn get_id(&self) -> Uuid {
         self.id
     }
+    pub fn get_handle(&self) -> EntHandle {
+        self.handle
+    }
+
+    // An inactive entity and all of its descendants are skipped by Manager::update, events and queries
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+    }
+    // The entity's own flag, regardless of its ancestors
+    pub fn is_active_self(&self) -> bool {
+        self.active
+    }
+    // False if this entity or any of its ancestors is inactive, whether or not they are borrowed
+    pub fn is_active(&self) -> bool {
+        self.active && self.parent_addr.chain_active()
+    }
+
+    // Tags are free-form labels to find entities by, see Manager::find_by_tag and QueryFilter::tag
+    // Returns false if the entity already had the tag
+    pub fn add_tag(&mut self, tag: &str) -> bool {
+        if self.has_tag(tag) {
+            return false;
+        }
+        self.tags.push(tag.to_string());
+        true
+    }
+    // Returns false if the entity didn't have the tag
+    pub fn remove_tag(&mut self, tag: &str) -> bool {
+        let len = self.tags.len();
+        self.tags.retain(|other| other != tag);
+        self.tags.len() != len
+    }
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|other| other == tag)
+    }
+    // In the order they were added
+    pub fn get_tags(&self) -> &[String] {
+        &self.tags
+    }
+    // Replaces every tag, dropping duplicates
+    pub fn set_tags(&mut self, tags: Vec<String>) {
+        self.tags.clear();
+        tags.iter().for_each(|tag| { self.add_tag(tag); });
+    }
+
+    // Bitmask of the layers the entity is in
+    pub fn get_layers(&self) -> u32 {
+        self.layers
+    }
+    pub fn set_layers(&mut self, layers: u32) {
+        self.layers = layers;
+    }
+    // True if the entity is in any of the layers in mask
+    pub fn in_layers(&self, mask: u32) -> bool {
+        self.layers & mask != 0
+    }
 
     // A list of all elements with the type information erased
     pub fn erased_elements(&mut self) ->                        Vec<EleAddrErased> {
@@ -33,12 +269,44 @@ impl Entity {
     }
 
     // Create element, can occur at any time
+    // Fails if an element of type T is already present, unless T is multi_instance
     pub fn add_element<T: Element>(&mut self, val: T) ->        Result<EleAddr<T>, String> {
-        if self.query_element_addr::<T>().valid() {
+        if !T::multi_instance() && self.query_element_addr::<T>().valid() {
             return Err(format!("Element of type \"{}\" is already present", std::any::type_name::<T>()));
         }
         self.elements.push(ElementHolder::new(val, self.self_addr.clone()));
-        Ok(self.query_element_addr::<T>())
+        if let Some(index) = self.element_index.upgrade() {
+            let holder = self.elements.last_mut().unwrap();
+            let info = TypeInfo {
+                name: holder.get_element_type_name(),
+                priority: holder.get_update_priority(),
+                after: holder.get_update_after().to_vec(),
+                events: holder.get_event_types().to_vec()
+            };
+            index.lock().insert(holder.make_addr_erased(), info);
+        }
+        // Element::on_added needs the Manager, so it's called on the next resolve
+        if let Some(queue) = self.added_queue.upgrade() {
+            queue.lock().push(self.elements.last_mut().unwrap().make_addr_erased());
+        }
+        Ok(self.elements.last_mut().unwrap().make_addr::<T>())
+    }
+    // Moves addr to be the index-th element of its type, or the last one if there are fewer
+    // Used to restore the order of multi_instance elements
+    pub fn move_element_instance(&mut self, addr: &EleAddrErased, index: usize) {
+        let from = match self.elements.iter_mut().position(|ele| ele.make_addr_erased().eq(addr)) {
+            Some(from) => from,
+            None => return
+        };
+        let holder = self.elements.remove(from);
+        let id = holder.get_element_type_id();
+        let to = self.elements.iter()
+        .enumerate()
+        .filter(|(_, ele)| ele.get_element_type_id() == id)
+        .map(|(i, _)| i)
+        .nth(index)
+        .unwrap_or(self.elements.len());
+        self.elements.insert(to, holder);
     }
     
     // Querying addresses
@@ -55,6 +323,24 @@ impl Entity {
         .find(|ele| ele.get_element_type_id() == *id)
         .map_or(EleAddrErased::new(), |ele| ele.make_addr_erased())
     }
+    // Every element of type T in the order they were added; the query_element functions return the first one
+    pub fn query_elements<T: Element>(&mut self) ->             Vec<EleAddr<T>> {
+        self.elements.iter_mut()
+        .filter(|ele| ele.get_element_type_id() == TypeId::of::<T>())
+        .map(|ele| ele.make_addr::<T>())
+        .collect()
+    }
+    pub fn query_elements_by_id(&mut self, id: &TypeId) ->      Vec<EleAddrErased> {
+        self.elements.iter_mut()
+        .filter(|ele| ele.get_element_type_id() == *id)
+        .map(|ele| ele.make_addr_erased())
+        .collect()
+    }
+    pub(crate) fn element_instance_index(&self, id: TypeId, internal: &WeakShared<ElementState>) -> Option<usize> {
+        self.elements.iter()
+        .filter(|ele| ele.get_element_type_id() == id)
+        .position(|ele| ele.holds(internal))
+    }
     
     // Querying conveniance functions (just call get_ref/_mut on the address)
     pub fn query_element<T: Element>(&mut self) ->              Option<EleRef<T>> {
@@ -76,8 +362,9 @@ impl Entity {
     pub fn get_children(&self) ->                               Vec<EntAddr> {
         self.children_addrs.clone()
     }
+    // All descendants in depth-first pre-order, excluding this entity
     pub fn get_all_children(&self) ->                           Vec<EntAddr> {
-        todo!();
+        DepthFirstIter::new(self.get_children()).collect()
     }
     
     // Private function used by Manager; noop if the element isn't found
@@ -86,28 +373,51 @@ impl Entity {
         =self.elements.iter_mut()
         .position(|ele| ele.make_addr_erased().eq(&addr))
         {
+            if let Some(index) = self.element_index.upgrade() {
+                index.lock().remove(&addr, self.id);
+            }
             self.elements.remove(element_index);
         }
     }
 }
 
+impl Drop for Entity {
+    fn drop(&mut self) {
+        if let Some(index) = self.element_index.upgrade() {
+            let mut index = index.lock();
+            for ele in self.elements.iter_mut() {
+                index.remove(&ele.make_addr_erased(), self.id);
+            }
+        }
+    }
+}
+
 pub struct EntityHolder {
     data: *mut Entity, // must be cleaned up with a Box::from_raw
-    internal: Rc<Cell<i64>>
+    internal: Shared<BorrowCount>
 }
 
 impl EntityHolder {
     pub fn new(name: String) -> Self {
+        Self::with_id(name, Uuid::new_v4())
+    }
+    pub fn with_id(name: String, id: Uuid) -> Self {
         Self {
             data: Box::into_raw(Box::new(Entity {
                 elements: Vec::new(),
+                element_index: WeakShared::new(),
+                added_queue: WeakShared::new(),
                 self_addr: EntAddr::new(),
                 parent_addr: EntAddr::new(),
                 children_addrs: vec!(),
-                id: Uuid::new_v4(),
+                id,
+                handle: EntHandle::invalid(),
+                active: true,
+                tags: Vec::new(),
+                layers: DEFAULT_LAYERS,
                 name
             })),
-            internal: Rc::new(Cell::new(0))
+            internal: Shared::new(BorrowCount::new())
         }
     }
     pub fn make_addr(&self) -> EntAddr {
@@ -116,11 +426,17 @@ impl EntityHolder {
 
         EntAddr {
             data: b,
-            internal: Rc::downgrade(&self.internal)
+            internal: Shared::downgrade(&self.internal)
         }
     }
 }
 
+// Access to the Entity goes through the atomic borrow count with the sync feature, like EntAddr
+#[cfg(feature = "sync")]
+unsafe impl Send for EntityHolder {}
+#[cfg(feature = "sync")]
+unsafe impl Sync for EntityHolder {}
+
 impl Drop for EntityHolder {
     fn drop(&mut self) {
         unsafe { Box::from_raw(self.data) };
@@ -133,7 +449,7 @@ impl Drop for EntityHolder {
 #[derive(Clone)]
 pub struct EntAddr {
     data: *mut Entity,
-    internal: Weak<Cell<i64>>
+    internal: WeakShared<BorrowCount>
 }
 
 impl PartialEq for EntAddr {
@@ -156,8 +472,8 @@ impl Serialize for EntAddr {
         S: serde::Serializer {
         // intentionally provoke a panic here if valid but get_ref fails
         match self.valid() {
-            true => serializer.serialize_i64(self.get_ref().unwrap().get_id().as_u128() as i64),
-            false => serializer.serialize_i64(0i64)
+            true => SerializedEntId::from_id(self.get_ref().unwrap().get_id()).serialize(serializer),
+            false => serializer.serialize_none()
         }
     }
 }
@@ -166,9 +482,7 @@ impl<'de> Deserialize<'de> for EntAddr {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
     D: serde::Deserializer<'de> {
-        let v: i64 = Deserialize::deserialize(deserializer)?;
-
-        Ok(map_id(Uuid::from_u128(v as u128)))
+        deserialize_ent_addr(deserializer)
     }
 }
 
@@ -179,77 +493,137 @@ impl EntAddr {
     pub fn new() -> Self {
         Self {
             data: std::ptr::null_mut(),
-            internal: Weak::new()
+            internal: WeakShared::new()
         }
     }
     pub fn valid(&self) -> bool {
         self.internal.strong_count() > 0
     }
-    pub fn get_ref(&self) -> Option<EntRef> {
+    #[track_caller]
+    pub fn try_get_ref(&self) -> Result<EntRef<'_>, BorrowError> {
         match self.internal.upgrade() {
-            Some(rc) => {
-                if rc.get() >= 0 {
-                    return Some(EntRef::new(
-                        unsafe { &*self.data },
-                        self.internal.clone()
-                    ))
-                }
-                None
-            },
-            None => None
+            Some(_) => EntRef::try_new(unsafe { &*self.data }, self.internal.clone()),
+            None => Err(BorrowError::Dead(std::any::type_name::<Entity>()))
         }
     }
-    pub fn get_ref_mut(&self) -> Option<EntRefMut> {
+    #[track_caller]
+    pub fn try_get_ref_mut(&self) -> Result<EntRefMut<'_>, BorrowError> {
         match self.internal.upgrade() {
-            Some(rc) => {
-                if rc.get() == 0 {
-                    return Some(EntRefMut::new(
-                        unsafe { &mut *self.data },
-                        self.internal.clone()
-                    ))
-                }
-                None
-            },
-            None => None
+            Some(_) => EntRefMut::try_new(unsafe { &mut *self.data }, self.internal.clone()),
+            None => Err(BorrowError::Dead(std::any::type_name::<Entity>()))
+        }
+    }
+    // None if the entity is dead or the borrow conflicts
+    #[track_caller]
+    pub fn get_ref(&self) -> Option<EntRef> {
+        self.try_get_ref().ok()
+    }
+    #[track_caller]
+    pub fn get_ref_mut(&self) -> Option<EntRefMut> {
+        self.try_get_ref_mut().ok()
+    }
+    // False for dead entities, see Entity::is_active
+    pub fn is_active(&self) -> bool {
+        self.valid() && self.chain_active()
+    }
+    // Whether this entity and its ancestors are all active, true once the chain ends
+    fn chain_active(&self) -> bool {
+        let mut curr = self.clone();
+        while curr.valid() {
+            // only the flag is read, no reference to the whole entity is made
+            if !unsafe { std::ptr::addr_of!((*curr.data).active).read() } {
+                return false;
+            }
+            curr = curr.peek_parent().unwrap_or_else(EntAddr::new);
+        }
+        true
+    }
+    // The parent and children of the entity, read without borrowing it so a held EntRefMut doesn't get in the way
+    // Only that field is read, no reference to the whole entity is made; None for dead entities
+    pub(crate) fn peek_parent(&self) -> Option<EntAddr> {
+        self.internal.upgrade()?;
+        Some(unsafe { (*std::ptr::addr_of!((*self.data).parent_addr)).clone() })
+    }
+    pub(crate) fn peek_children(&self) -> Option<Vec<EntAddr>> {
+        self.internal.upgrade()?;
+        Some(unsafe { (*std::ptr::addr_of!((*self.data).children_addrs)).clone() })
+    }
+
+    // Hierarchy traversal starting at (and including) this entity, except for ancestors
+    pub fn iter_depth_first(&self) ->                           DepthFirstIter {
+        DepthFirstIter::new(vec![self.clone()])
+    }
+    pub fn iter_post_order(&self) ->                            PostOrderIter {
+        PostOrderIter::new(vec![self.clone()])
+    }
+    pub fn iter_breadth_first(&self) ->                         BreadthFirstIter {
+        BreadthFirstIter::new(vec![self.clone()])
+    }
+    pub fn iter_ancestors(&self) ->                             AncestorIter {
+        AncestorIter::new(self.clone())
+    }
+    // The names from the root entity down to this one joined by '/', e.g. "Level/Door/Hinge"
+    // None for dead entities, or if this entity or one of its ancestors is mutably borrowed
+    // Manager::find_by_path resolves it back to this entity unless a name contains '/' or an earlier sibling shares the path
+    pub fn path(&self) ->                                       Option<String> {
+        let mut names = Vec::new();
+        let mut curr = self.clone();
+        while curr.valid() {
+            let parent = {
+                let ent = curr.try_get_ref().ok()?;
+                names.push(ent.name.clone());
+                ent.get_parent()
+            };
+            curr = parent;
+        }
+        names.reverse();
+        match names.is_empty() {
+            true => None,
+            false => Some(names.join("/"))
         }
     }
 }
 
 pub struct EntRef<'a> {
     data: &'a Entity,
-    internal: Weak<Cell<i64>>
+    internal: WeakShared<BorrowCount>,
+    _token: BorrowToken
 }
 
 pub struct EntRefMut<'a> {
     data: &'a mut Entity,
-    internal: Weak<Cell<i64>>
+    internal: WeakShared<BorrowCount>,
+    _token: BorrowToken
 }
 
 impl<'a> EntRef<'a> {
-    pub fn new(data: &'a Entity, internal: Weak<Cell<i64>>) -> Self {
-        if let Some(rc) = internal.upgrade() {
-            assert!(rc.get() >= 0, "Instance of Entity is already borrowed mutably");
+    #[track_caller]
+    pub fn try_new(data: &'a Entity, internal: WeakShared<BorrowCount>) -> Result<Self, BorrowError> {
+        let rc = internal.upgrade().ok_or(BorrowError::Dead(std::any::type_name::<Entity>()))?;
+        rc.acquire(false, std::any::type_name::<Entity>())?;
 
-            rc.set(rc.get() + 1);
-        } else {
-            panic!("Immutable Reference to Entity attempted to be created from a dead address");
-        }
-
-        Self { data, internal }
+        let _token = BorrowToken::new(borrow_key(&internal), std::any::type_name::<Entity>(), false);
+        Ok(Self { data, internal, _token })
+    }
+    #[track_caller]
+    pub fn new(data: &'a Entity, internal: WeakShared<BorrowCount>) -> Self {
+        let key = borrow_key(&internal);
+        Self::try_new(data, internal).unwrap_or_else(|err| panic!("{}{}", err, describe_holders(key)))
     }
 }
 impl<'a> EntRefMut<'a> {
-    pub fn new(data: &'a mut Entity, internal: Weak<Cell<i64>>) -> Self {
-        if let Some(rc) = internal.upgrade() {
-            assert!(rc.get() <= 0, "Instance of Entity is already borrowed immutably");
-            assert!(rc.get() >= 0, "Instance of Entity is already borrowed mutably");
+    #[track_caller]
+    pub fn try_new(data: &'a mut Entity, internal: WeakShared<BorrowCount>) -> Result<Self, BorrowError> {
+        let rc = internal.upgrade().ok_or(BorrowError::Dead(std::any::type_name::<Entity>()))?;
+        rc.acquire(true, std::any::type_name::<Entity>())?;
 
-            rc.set(rc.get() - 1);
-        } else {
-            panic!("Mutable Reference to Entity attempted to be created from a dead address");
-        }
-
-        Self { data, internal }
+        let _token = BorrowToken::new(borrow_key(&internal), std::any::type_name::<Entity>(), true);
+        Ok(Self { data, internal, _token })
+    }
+    #[track_caller]
+    pub fn new(data: &'a mut Entity, internal: WeakShared<BorrowCount>) -> Self {
+        let key = borrow_key(&internal);
+        Self::try_new(data, internal).unwrap_or_else(|err| panic!("{}{}", err, describe_holders(key)))
     }
 }
 impl<'a> Drop for EntRef<'a> {
@@ -259,8 +633,7 @@ impl<'a> Drop for EntRef<'a> {
             Some(rc) => rc,
             None => panic!("When dropping immutable reference of Entity, the holder was already destroyed")
         };
-        rc.set(rc.get() - 1);
-        assert!(rc.get() >= 0, "Instance of Entity's ref count somehow dropped below zero");
+        assert!(rc.release(false) >= 0, "Instance of Entity's ref count somehow dropped below zero");
     }
 }
 impl<'a> Drop for EntRefMut<'a> {
@@ -270,8 +643,7 @@ impl<'a> Drop for EntRefMut<'a> {
             Some(rc) => rc,
             None => panic!("When dropping mutable reference of Entity, the holder was already destroyed")
         };
-        rc.set(rc.get() + 1);
-        assert!(rc.get() == 0, "Instance of Entity's ref count didn't equal zero when dropping mutable reference");
+        assert!(rc.release(true) == 0, "Instance of Entity's ref count didn't equal zero when dropping mutable reference");
     }
 }
 impl<'a> Deref for EntRef<'a> {
@@ -294,12 +666,35 @@ impl<'a> DerefMut for EntRefMut<'a> {
     }
 }
 
+#[cfg(not(feature = "sync"))]
+type DeferredFn = Box<dyn FnOnce(&mut Manager)>;
+#[cfg(feature = "sync")]
+type DeferredFn = Box<dyn FnOnce(&mut Manager) + Send + Sync>;
+
+// The order Manager::update visits entities in, within each update priority
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateOrder {
+    // The order the entities were created in
+    Creation,
+    // Depth first from the root entities, so parents are updated before their children
+    Hierarchy
+}
+
 // Manager
 pub struct Manager {
     entities: Vec<EntityHolder>,
     root_entities: Vec<EntAddr>,
     entity_destroy_queue: HashSet<EntAddr>,
-    element_destroy_queue: HashSet<EleAddrErased>
+    element_destroy_queue: HashSet<EleAddrErased>,
+    element_index: Shared<SharedLock<ElementIndex>>,
+    added_queue: Shared<SharedLock<Vec<EleAddrErased>>>,
+    entity_ids: HashMap<Uuid, EntAddr>,
+    entity_slots: Slots<EntAddr>,
+    schedule: Schedule,
+    deferred: Vec<DeferredFn>,
+    update_order: UpdateOrder,
+    events: EventBus,
+    resources: Resources
 }
 
 impl Manager {
@@ -308,17 +703,107 @@ impl Manager {
             entities: Vec::new(),
             root_entities: Vec::new(),
             entity_destroy_queue: HashSet::new(),
-            element_destroy_queue: HashSet::new()
+            element_destroy_queue: HashSet::new(),
+            element_index: Shared::new(SharedLock::new(ElementIndex::default())),
+            added_queue: Shared::new(SharedLock::new(Vec::new())),
+            entity_ids: HashMap::new(),
+            entity_slots: Slots::default(),
+            schedule: Schedule::new(),
+            deferred: Vec::new(),
+            update_order: UpdateOrder::Creation,
+            events: EventBus::new(),
+            resources: {
+                let mut resources = Resources::new();
+                resources.insert(Time::new());
+                resources
+            }
         }
     }
     
     // Creation and destruction functions
     pub fn create_entity(&mut self, name: String) ->    EntAddr {
-        self.entities.push(EntityHolder::new(name));
+        self.create_entity_with_id(name, Uuid::new_v4()).expect("Freshly generated entity id collided")
+    }
+    // Fails if the id is nil or already used by an entity of this Manager
+    pub fn create_entity_with_id(&mut self, name: String, id: Uuid) -> Result<EntAddr, String> {
+        if id.is_nil() || self.entity_ids.contains_key(&id) {
+            return Err(format!("Entity id {} is already in use", id));
+        }
+        self.entities.push(EntityHolder::with_id(name, id));
         let res = self.entities.last_mut().unwrap().make_addr();
+        self.entity_ids.insert(id, res.clone());
         self.root_entities.push(res.clone());
-        res.get_ref_mut().expect("Entity that was just created should exist").self_addr = res.clone();
-        res
+        let key = self.entity_slots.insert(res.clone());
+        {
+            let mut ent = res.get_ref_mut().expect("Entity that was just created should exist");
+            ent.handle = EntHandle::new(key);
+            ent.self_addr = res.clone();
+            ent.element_index = Shared::downgrade(&self.element_index);
+            ent.added_queue = Shared::downgrade(&self.added_queue);
+        }
+        Ok(res)
+    }
+    // Copies addr, and with deep all of its descendants, under the same parent and returns the copy
+    // Elements are copied with Clone; references into the copied entities are then moved over to the copies
+    // by round tripping each copied element through ecs_serialize/ecs_deserialize
+    pub fn duplicate_entity(&mut self, addr: EntAddr, deep: bool) -> EntAddr {
+        assert!(addr.valid());
+        let originals = match deep {
+            true => addr.iter_depth_first().collect::<Vec<EntAddr>>(),
+            false => vec![addr.clone()]
+        };
+
+        let mut context = DeserializeContext::new();
+        let copies = originals.iter()
+        .map(|orig| {
+            let (id, name, active, tags, layers) = {
+                let ent = orig.get_ref().unwrap();
+                (ent.get_id(), ent.name.clone(), ent.is_active_self(), ent.tags.clone(), ent.layers)
+            };
+            let copy = context.set_mapping_fresh(id, name, self);
+            {
+                let mut copy_ref = copy.get_ref_mut().unwrap();
+                copy_ref.active = active;
+                copy_ref.tags = tags;
+                copy_ref.layers = layers;
+            }
+            copy
+        })
+        .collect::<Vec<EntAddr>>();
+
+        // Depth first order visits every parent before its children, so all but the first copy have a copied parent
+        for (i, (orig, copy)) in originals.iter().zip(copies.iter()).enumerate() {
+            let parent = orig.get_ref().unwrap().get_parent();
+            let parent = match i {
+                0 => parent,
+                _ => context.map_id(parent.get_ref().unwrap().get_id())
+            };
+            self.reparent(copy.clone(), parent).unwrap();
+        }
+
+        let copied_eles = originals.iter().zip(copies.iter())
+        .flat_map(|(orig, copy)| {
+            orig.get_ref_mut().unwrap()
+            .erased_elements()
+            .iter()
+            .map(|ele| {
+                let dup = ele.get_ref().unwrap().ecs_duplicate_into(&mut copy.get_ref_mut().unwrap());
+                dup.set_enabled(ele.is_enabled());
+                dup
+            })
+            .filter(|ele| ele.valid())
+            .collect::<Vec<EleAddrErased>>()
+        })
+        .collect::<Vec<EleAddrErased>>();
+
+        for mut ele in copied_eles.into_iter() {
+            let payload = ele.get_ref().unwrap().ecs_serialize();
+            context.map_live_ids(self, &payload);
+            // An element whose data doesn't round trip keeps the references of its original
+            let _ = context.scope(|| ele.get_ref_mut().unwrap().ecs_deserialize(payload));
+        }
+
+        copies[0].clone()
     }
     pub fn destroy_entity(&mut self, addr: EntAddr) {
         self.entity_destroy_queue.insert(addr);
@@ -326,25 +811,44 @@ impl Manager {
     pub fn destroy_element(&mut self, addr: EleAddrErased) {
         self.element_destroy_queue.insert(addr);
     }
+    // Runs f on the next resolve, e.g. to add elements or create entities from a system without
+    // the other systems of the stage seeing them (those made directly show up right away)
+    pub fn defer(&mut self, f: impl FnOnce(&mut Manager) + MaybeSync + 'static) {
+        self.deferred.push(Box::new(f));
+    }
     
     // Manager activity functions
+    // Runs deferred functions and the on_added hooks of new elements, then destroys queued entities and elements
     pub fn resolve(&mut self) {
+        for f in std::mem::take(&mut self.deferred).into_iter() {
+            f(self);
+        }
+        self.notify_added();
+
         {
             let mut tmp_destroy_queue = self.entity_destroy_queue.iter().map(|ent| ent.clone()).collect::<Vec<EntAddr>>();
             self.entity_destroy_queue.clear();
 
             while !tmp_destroy_queue.is_empty() {
                 let destroying = tmp_destroy_queue[tmp_destroy_queue.len() - 1].clone();
-                self.reparent(destroying.clone(), EntAddr::new()).unwrap();
                 tmp_destroy_queue.remove(tmp_destroy_queue.len() - 1);
+                // already destroyed as the descendant of another queued entity
+                if !destroying.valid() {
+                    continue;
+                }
+                self.notify_elements(&destroying, |ele, man, owner| ele.on_owner_destroyed(man, owner));
+                self.reparent_silently(destroying.clone(), EntAddr::new()).unwrap();
                 let children = destroying.get_ref().unwrap().get_children();
                 for child in children.into_iter() {
-                    self.reparent(child.clone(), EntAddr::new()).unwrap();
+                    self.reparent_silently(child.clone(), EntAddr::new()).unwrap();
                     assert!(child.valid());
                     tmp_destroy_queue.push(child);
                 }
                 let root_index = self.root_entities.iter().position(|ent| *ent == destroying).unwrap();
                 self.root_entities.remove(root_index);
+                self.entity_ids.remove(&destroying.get_ref().unwrap().get_id());
+                let handle = destroying.get_ref().unwrap().get_handle();
+                self.entity_slots.remove(handle.key());
                 let index = self.find_ent_index(&destroying).unwrap();
                 self.entities.remove(index);
             }
@@ -354,8 +858,16 @@ impl Manager {
             let cloned_destroy_queue = self.element_destroy_queue.clone();
             self.element_destroy_queue.clear();
             for to_destroy in cloned_destroy_queue.iter() {
+                // still in use (e.g. resolve was called from its own update), destroyed by a later resolve
+                if to_destroy.is_borrowed() {
+                    self.element_destroy_queue.insert(to_destroy.clone());
+                    continue;
+                }
                 if let Some(destroy_index) = self.find_ent_index(&to_destroy.get_owner()) {
                     let addr = self.entities[destroy_index].make_addr();
+                    if let Ok(mut ele) = to_destroy.clone().try_get_ref_mut() {
+                        ele.untracked().on_removed(self, addr.clone());
+                    }
                     let mut r = addr.get_ref_mut().unwrap();
                     let ent_raw = r.deref_mut();
                     ent_raw.remove_element(to_destroy.clone());
@@ -363,45 +875,432 @@ impl Manager {
             }
         }
     }
-    pub fn update(&mut self) {
-        let mut index = 0 as usize;
-        while index < self.entities.len() {
-            let ent_addr = self.entities[index].make_addr();
+    // Forgets the changes of the last update, makes the events sent since then readable and passes them
+    // to Element::on_event, then runs every stage of the schedule, with Element::update at the start of Stage::Update
+    // dt is the time passed since the last update in seconds, which the Time resource makes available during it
+    // With a fixed timestep set on Time, Element::fixed_update runs for each whole step between PreUpdate and Update
+    pub fn update(&mut self, dt: f32) {
+        self.clear_changes();
+        if !self.has_resource::<Time>() {
+            self.insert_resource(Time::new());
+        }
+        let fixed_steps = self.resource_mut::<Time>().unwrap().advance(dt);
+        self.events.swap();
+        self.dispatch_events();
+        self.run_stage(Stage::PreUpdate);
+        for _ in 0..fixed_steps {
+            self.update_elements(|ele, man, owner| ele.fixed_update(man, owner));
+        }
+        self.update_elements(|ele, man, owner| ele.update(man, owner));
+        self.run_stage(Stage::Update);
+        self.run_stage(Stage::PostUpdate);
+        self.run_stage(Stage::Render);
+    }
+    // Runs the systems of the stage, those caught in an ordering cycle last (see Schedule::cycle)
+    // Destroys and defer()ed changes made by the systems are resolved once all of them have run, as are
+    // the systems they add or remove; entities, elements and reparents they create take effect right away
+    pub fn run_stage(&mut self, stage: Stage) {
+        let order = self.schedule.begin_stage(stage);
+        for i in order.into_iter() {
+            if let Some(mut system) = self.schedule.take_system(i) {
+                system.run(self);
+                self.schedule.return_system(i, system);
+            }
+        }
+        self.schedule.end_stage();
+        self.resolve();
+    }
+    // Elements are updated in passes, one per update priority level; each pass visits entities in update_order
+    // and updates an entity's elements in the order they were added
+    // Entities and element types created during an update are updated from the next one on
+    // Inactive entities and disabled elements are skipped
+    fn update_elements(&mut self, hook: impl Fn(&mut dyn Element, &mut Manager, EntAddr)) {
+        let levels = self.element_index.lock().update_levels();
+        let mut passes = levels.values().copied().collect::<Vec<i64>>();
+        passes.sort();
+        passes.dedup();
 
-            {
-                let mut element_index = 0 as usize;
-                let mut elements = ent_addr.get_ref_mut().unwrap().erased_elements();
-                while element_index < elements.len() {
-                    elements[element_index].get_ref_mut().unwrap().update(self, ent_addr.clone());
-                    element_index += 1;
+        for level in passes.into_iter() {
+            let ents = match self.update_order {
+                UpdateOrder::Creation => self.all_entities(),
+                UpdateOrder::Hierarchy => self.iter_depth_first().collect()
+            };
+
+            for ent_addr in ents.into_iter() {
+                // destroyed while resolving an earlier entity
+                if !ent_addr.valid() || !ent_addr.is_active() {
+                    continue;
                 }
-            }
 
-            self.resolve();
+                let elements = ent_addr.get_ref_mut().unwrap().erased_elements();
+                for mut ele in elements.into_iter() {
+                    if ele.get_element_type_id().and_then(|id| levels.get(&id)) != Some(&level) || !ele.is_enabled() {
+                        continue;
+                    }
+                    if let Some(mut ele_ref) = ele.get_ref_mut() {
+                        hook(&mut *ele_ref, self, ent_addr.clone());
+                    }
+                }
 
-            index += 1;
+                self.resolve();
+            }
         }
 
         self.resolve();
     }
+    // The update_after cycles that the last update broke, one per line; None if there were none
+    pub fn update_cycle(&self) ->                               Option<String> {
+        self.element_index.lock().cycle.clone()
+    }
+    pub fn set_update_order(&mut self, order: UpdateOrder) {
+        self.update_order = order;
+    }
+    pub fn get_update_order(&self) ->                           UpdateOrder {
+        self.update_order
+    }
+    
+    // Resources, singletons looked up by type
+    // Replaces the resource of the same type, if any; panics if that one is borrowed
+    #[track_caller]
+    pub fn insert_resource<R: MaybeSync + 'static>(&mut self, res: R) {
+        self.resources.insert(res);
+    }
+    // None if there is no such resource, panics if it's borrowed
+    #[track_caller]
+    pub fn remove_resource<R: 'static>(&mut self) ->            Option<R> {
+        self.resources.remove::<R>()
+    }
+    pub fn try_remove_resource<R: 'static>(&mut self) ->        Result<R, BorrowError> {
+        self.resources.try_remove::<R>()
+    }
+    pub fn has_resource<R: 'static>(&self) ->                   bool {
+        self.resources.contains::<R>()
+    }
+    // None if there is no such resource, panics if it's already borrowed
+    #[track_caller]
+    pub fn resource<'a, R: 'static>(&self) ->                   Option<ResRef<'a, R>> {
+        self.resources.get::<R>()
+    }
+    #[track_caller]
+    pub fn resource_mut<'a, R: 'static>(&self) ->               Option<ResRefMut<'a, R>> {
+        self.resources.get_mut::<R>()
+    }
+    #[track_caller]
+    pub fn try_resource<'a, R: 'static>(&self) ->               Result<ResRef<'a, R>, BorrowError> {
+        self.resources.try_get::<R>()
+    }
+    #[track_caller]
+    pub fn try_resource_mut<'a, R: 'static>(&self) ->           Result<ResRefMut<'a, R>, BorrowError> {
+        self.resources.try_get_mut::<R>()
+    }
+
+    // Events
+    pub fn send<E: MaybeSync + 'static>(&mut self, event: E) {
+        self.events.send(None, event);
+    }
+    // Events sent to an entity that is destroyed before they are read are dropped
+    pub fn send_to<E: MaybeSync + 'static>(&mut self, target: EntAddr, event: E) {
+        self.events.send(Some(target), event);
+    }
+    // Events of type E sent during the previous update; the target is None for broadcasts
+    pub fn read_events<E: MaybeSync + 'static>(&self) ->        Vec<(Option<EntAddr>, Shared<E>)> {
+        self.events.read::<E>()
+        .into_iter()
+        .filter(|(target, _)| target.as_ref().is_none_or(|target| target.valid()))
+        .collect()
+    }
+    // The events of type E broadcast or sent to ent
+    pub fn read_events_for<E: MaybeSync + 'static>(&self, ent: &EntAddr) -> Vec<Shared<E>> {
+        self.events.read::<E>()
+        .into_iter()
+        .filter(|(target, _)| target.as_ref().is_none_or(|target| target == ent))
+        .map(|(_, event)| event)
+        .collect()
+    }
+    // Broadcasts only visit the elements of the types handling them, type by type in the order the types appeared
+    fn dispatch_events(&mut self) {
+        let records = self.events.read_all().to_vec();
+        for record in records.into_iter() {
+            let handlers = match &record.target {
+                Some(target) => target.get_ref_mut().map_or_else(Vec::new, |mut ent| {
+                    ent.elements.iter_mut()
+                    .filter(|ele| ele.handles_event(record.id))
+                    .map(|ele| ele.make_addr_erased())
+                    .collect()
+                }),
+                None => self.element_index.lock().handlers(&record.id)
+            };
+            for mut ele in handlers.into_iter() {
+                let owner = ele.get_owner();
+                if !ele.is_enabled() || !owner.is_active() {
+                    continue;
+                }
+                if let Some(mut ele_ref) = ele.get_ref_mut() {
+                    ele_ref.on_event(self, owner, &*record.event);
+                }
+            }
+        }
+        self.resolve();
+    }
+
+    // Systems
+    pub fn add_system(&mut self, stage: Stage, name: &str, system: impl System) -> Result<&mut SystemEntry, String> {
+        self.schedule.add_system(stage, name, system)
+    }
+    pub fn schedule(&self) ->                                   &Schedule {
+        &self.schedule
+    }
+    pub fn schedule_mut(&mut self) ->                           &mut Schedule {
+        &mut self.schedule
+    }
     
     // Querying functions
+    // Every element of type T in no particular order, including disabled ones and ones on inactive entities
     pub fn of_type<T: Element>(&mut self) ->                    Vec<EleAddr<T>> {
-        self.entities.iter_mut()
-        .filter(|ent| ent.make_addr().get_ref_mut().unwrap().query_element_addr::<T>().valid() )
-        .map(|ent| { ent.make_addr().get_ref_mut().unwrap().query_element_addr::<T>() })
+        self.element_index.lock()
+        .of_type(&TypeId::of::<T>())
+        .iter()
+        .map(|ele| ele.downcast::<T>())
+        .collect()
+    }
+    #[cfg(feature = "sync")]
+    pub(crate) fn of_type_id(&self, id: &TypeId) ->             Vec<EleAddrErased> {
+        self.element_index.lock().of_type(id).to_vec()
+    }
+    // Change detection, covering everything since the last clear_changes (called at the start of every update)
+    // Elements of type T added since then that are still alive
+    pub fn added<T: Element>(&self) ->                          Vec<EleAddr<T>> {
+        self.element_index.lock().added.iter()
+        .filter(|ele| ele.valid() && ele.get_element_type_id() == Some(TypeId::of::<T>()))
+        .map(|ele| ele.downcast::<T>())
+        .collect()
+    }
+    // Elements of type T that were added or written to through a mutable reference
+    // The Manager calling Element::update, on_event or a lifecycle hook on an element counts as a write
+    pub fn changed<T: Element>(&self) ->                        Vec<EleAddr<T>> {
+        self.element_index.lock()
+        .of_type(&TypeId::of::<T>())
+        .iter()
+        .filter(|ele| ele.is_changed())
+        .map(|ele| ele.downcast::<T>())
+        .collect()
+    }
+    // Ids of the entities that lost an element of type T, once per removed element
+    pub fn removed<T: Element>(&self) ->                        Vec<Uuid> {
+        self.element_index.lock().removed.iter()
+        .filter(|(id, _)| *id == TypeId::of::<T>())
+        .map(|(_, owner)| *owner)
+        .collect()
+    }
+    pub fn clear_changes(&mut self) {
+        self.element_index.lock().clear_changes();
+    }
+    // Every entity matching Q, e.g. query::<(A, Option<B>, Without<C>)>(), along with its element addresses
+    // Inactive entities don't match and disabled elements count as absent
+    pub fn query<Q: QueryParam>(&mut self) ->                   Vec<(EntAddr, Q::Addr)> {
+        self.query_with::<Q>(&QueryFilter::new())
+    }
+    // Like query, but matching inactive entities and disabled elements too
+    pub fn query_including_inactive<Q: QueryParam>(&mut self) -> Vec<(EntAddr, Q::Addr)> {
+        self.query_with::<Q>(&QueryFilter::new().include_inactive())
+    }
+    // Like query, but only matching entities that pass filter, e.g. query_with::<A>(&QueryFilter::new().tag("enemy"))
+    pub fn query_with<Q: QueryParam>(&mut self, filter: &QueryFilter) -> Vec<(EntAddr, Q::Addr)> {
+        // Only entities holding the rarest required type can match
+        let mut required = Vec::new();
+        Q::required_types(&mut required);
+        let candidates = match required.iter().min_by_key(|id| self.element_index.lock().of_type(id).len()) {
+            // An entity holding several elements of a multi_instance type is a candidate once
+            Some(id) => {
+                let mut seen = HashSet::new();
+                self.element_index.lock().of_type(id).iter()
+                .map(|ele| ele.get_owner())
+                .filter(|ent| seen.insert(ent.clone()))
+                .collect()
+            },
+            None => self.all_entities()
+        };
+
+        candidates.into_iter()
+        .filter(|ent| filter.includes_inactive() || ent.is_active())
+        .filter_map(|ent| {
+            let mut ent_ref = ent.get_ref_mut()?;
+            if !filter.matches(&ent_ref) {
+                return None;
+            }
+            let addrs = Q::fetch(&mut ent_ref, filter.includes_inactive())?;
+            drop(ent_ref);
+            Some((ent, addrs))
+        })
+        .collect()
+    }
+    // Runs f on every entity matching Q with its elements borrowed mutably,
+    // skipping entities whose elements are already borrowed (e.g. the caller's own)
+    pub fn query_each<Q: QueryParam, F: for<'a> FnMut(EntAddr, Q::Ref<'a>)>(&mut self, f: F) {
+        self.query_each_with::<Q, F>(&QueryFilter::new(), f)
+    }
+    pub fn query_each_with<Q: QueryParam, F: for<'a> FnMut(EntAddr, Q::Ref<'a>)>(&mut self, filter: &QueryFilter, mut f: F) {
+        for (ent, mut addrs) in self.query_with::<Q>(filter) {
+            if let Some(refs) = Q::borrow(&mut addrs) {
+                f(ent, refs);
+            }
+        }
+    }
+    pub fn find_by_id(&self, id: Uuid) ->                       EntAddr {
+        self.entity_ids.get(&id).cloned().unwrap_or_else(EntAddr::new)
+    }
+    // The entity at path, a list of entity names separated by '/' starting at a root entity, e.g. "Level/Door/Hinge"
+    // Where siblings share a name the first one in child order that has the rest of the path is picked
+    pub fn find_by_path(&self, path: &str) ->                   EntAddr {
+        self.find_relative(&EntAddr::new(), path)
+    }
+    // Resolves path starting at from, e.g. "../Sibling" or "Child/Grandchild"
+    // ".." is the parent and "." the entity itself; a path starting with '/' or an invalid from starts at the roots
+    pub fn find_relative(&self, from: &EntAddr, path: &str) ->  EntAddr {
+        let start = match path.starts_with('/') {
+            true => EntAddr::new(),
+            false => from.clone()
+        };
+        self.resolve_path(start, &path.split('/').collect::<Vec<&str>>())
+    }
+    // An invalid node stands for the level above the root entities
+    fn resolve_path(&self, node: EntAddr, segments: &[&str]) -> EntAddr {
+        let (first, rest) = match segments.split_first() {
+            Some(split) => split,
+            None => return node
+        };
+        match *first {
+            "" | "." => self.resolve_path(node, rest),
+            ".." => match node.get_ref() {
+                Some(ent) => self.resolve_path(ent.get_parent(), rest),
+                None => EntAddr::new()
+            },
+            name => {
+                let children = match node.get_ref() {
+                    Some(ent) => ent.get_children(),
+                    None => self.root_entities()
+                };
+                children.into_iter()
+                .filter(|child| child.get_ref().is_some_and(|child| child.name == name))
+                .map(|child| self.resolve_path(child, rest))
+                .find(|found| found.valid())
+                .unwrap_or_else(EntAddr::new)
+            }
+        }
+    }
+    // The find_by functions return entities in creation order, skipping ones that are mutably borrowed
+    pub fn find_by_tag(&self, tag: &str) ->                     Vec<EntAddr> {
+        self.find_where(|ent| ent.has_tag(tag))
+    }
+    pub fn find_by_name(&self, name: &str) ->                   Vec<EntAddr> {
+        self.find_where(|ent| ent.name == name)
+    }
+    pub fn find_by_name_prefix(&self, prefix: &str) ->          Vec<EntAddr> {
+        self.find_where(|ent| ent.name.starts_with(prefix))
+    }
+    // Entities in any of the layers in mask
+    pub fn find_in_layers(&self, mask: u32) ->                  Vec<EntAddr> {
+        self.find_where(|ent| ent.in_layers(mask))
+    }
+    fn find_where(&self, pred: impl Fn(&Entity) -> bool) ->     Vec<EntAddr> {
+        self.all_entities().into_iter()
+        .filter(|ent| ent.get_ref().is_some_and(|ent| pred(&ent)))
         .collect()
     }
+    // An invalid address if the handle's entity was destroyed or the handle comes from another Manager
+    pub fn entity(&self, handle: EntHandle) ->                  EntAddr {
+        self.entity_slots.get(handle.key()).cloned().unwrap_or_else(EntAddr::new)
+    }
+    pub fn element<T: Element>(&self, handle: EleHandle<T>) ->  EleAddr<T> {
+        self.element_index.lock().slots.get(handle.key())
+        .map_or_else(EleAddr::new, |ele| ele.downcast::<T>())
+    }
     pub fn all_entities(&self) ->                               Vec<EntAddr> {
         self.entities.iter().map(|holder| holder.make_addr()).collect()
     }
     pub fn root_entities(&self) ->                                  Vec<EntAddr> {
         self.root_entities.clone()
     }
+    // Every live reference to this Manager's entities and elements, oldest first
+    #[cfg(feature = "borrow-tracking")]
+    pub fn outstanding_borrows(&self) ->                        Vec<BorrowRecord> {
+        let keys = self.entities.iter()
+        .map(|holder| Shared::as_ptr(&holder.internal) as *const () as usize)
+        .chain(self.element_index.lock().by_type.values().flatten().map(|ele| ele.tracking_key()))
+        .collect::<HashSet<usize>>();
+        outstanding_borrows().into_iter().filter(|record| keys.contains(&record.key)).collect()
+    }
+
+    // Hierarchy traversal over every root entity and their descendants
+    pub fn iter_depth_first(&self) ->                           DepthFirstIter {
+        DepthFirstIter::new(self.root_entities())
+    }
+    pub fn iter_post_order(&self) ->                            PostOrderIter {
+        PostOrderIter::new(self.root_entities())
+    }
+    pub fn iter_breadth_first(&self) ->                         BreadthFirstIter {
+        BreadthFirstIter::new(self.root_entities())
+    }
+    // Entities sharing a parent with addr, excluding addr; root entities are siblings of each other
+    pub fn siblings(&self, addr: &EntAddr) ->                   Vec<EntAddr> {
+        let parent = match addr.peek_parent() {
+            Some(parent) => parent,
+            None => return Vec::new()
+        };
+        let all = match parent.peek_children() {
+            Some(children) => children,
+            None => self.root_entities()
+        };
+        all.into_iter().filter(|sibling| sibling != addr).collect()
+    }
+
+    // Lifecycle hooks
+    // Calls on_added for the elements added since the last call, including ones added by the hooks themselves
+    // Elements that are borrowed at the moment wait for the next call
+    pub(crate) fn notify_added(&mut self) {
+        let mut waiting = Vec::new();
+        loop {
+            let added = std::mem::take(&mut *self.added_queue.lock());
+            if added.is_empty() {
+                break;
+            }
+            for mut ele in added.into_iter() {
+                if ele.is_borrowed() {
+                    waiting.push(ele);
+                    continue;
+                }
+                let owner = ele.get_owner();
+                if let Some(mut ele_ref) = ele.get_ref_mut() {
+                    ele_ref.on_added(self, owner);
+                }
+            }
+        }
+        self.added_queue.lock().extend(waiting);
+    }
+    // Calls hook on each element of ent, skipping elements that are borrowed at the moment
+    // (e.g. the element whose update made the change)
+    fn notify_elements(&mut self, ent: &EntAddr, hook: impl Fn(&mut dyn Element, &mut Manager, EntAddr)) {
+        let elements = ent.get_ref_mut().unwrap().erased_elements();
+        for mut ele in elements.into_iter().filter(|ele| !ele.is_borrowed()) {
+            if let Some(mut ele_ref) = ele.get_ref_mut() {
+                hook(&mut *ele_ref, self, ent.clone());
+            }
+        }
+    }
 
     // Hierarchy
     // performs cycle check, doesn't reparent if a cycle would be formed
+    // Calls on_parent_changed on the child's elements if the parent changed
     pub fn reparent(&mut self, child: EntAddr, parent: EntAddr) ->  Result<(), EntReferenceCycleError> {
+        assert!(child.valid());
+        let old_parent = child.get_ref().unwrap().get_parent();
+        self.reparent_silently(child.clone(), parent.clone())?;
+        if old_parent != parent {
+            self.notify_elements(&child, |ele, man, owner| ele.on_parent_changed(man, owner, old_parent.clone()));
+        }
+        Ok(())
+    }
+    fn reparent_silently(&mut self, child: EntAddr, parent: EntAddr) -> Result<(), EntReferenceCycleError> {
         {
             assert!(child.valid());
 
@@ -0,0 +1,53 @@
+use std::any::TypeId;
+
+use crate::entity::*;
+use crate::sync::*;
+
+// A sent event; target is None for broadcasts
+#[derive(Clone)]
+pub struct EventRecord {
+    pub id: TypeId,
+    pub target: Option<EntAddr>,
+    pub event: Shared<AnyShared>
+}
+
+// Double-buffered events
+// Events sent during one update are read during the next, after Manager::update swaps the buffers
+#[derive(Default)]
+pub struct EventBus {
+    current: Vec<EventRecord>,
+    next: Vec<EventRecord>
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self {
+            current: Vec::new(),
+            next: Vec::new()
+        }
+    }
+
+    pub fn send<E: MaybeSync + 'static>(&mut self, target: Option<EntAddr>, event: E) {
+        self.next.push(EventRecord {
+            id: TypeId::of::<E>(),
+            target,
+            event: Shared::new(event)
+        });
+    }
+    // Events of type E readable this update, in the order they were sent
+    pub fn read<E: MaybeSync + 'static>(&self) -> Vec<(Option<EntAddr>, Shared<E>)> {
+        self.current.iter()
+        .filter(|record| record.id == TypeId::of::<E>())
+        .map(|record| (record.target.clone(), record.event.clone().downcast::<E>().unwrap()))
+        .collect()
+    }
+    // Every event readable this update, of any type
+    pub fn read_all(&self) -> &[EventRecord] {
+        &self.current
+    }
+
+    // Drops the events of the previous update and makes the ones sent since readable
+    pub fn swap(&mut self) {
+        self.current = std::mem::take(&mut self.next);
+    }
+}
@@ -0,0 +1,132 @@
+use std::{fmt, hash::Hash, marker::PhantomData, sync::atomic::{AtomicU32, Ordering}};
+
+// Compact handles to entities and elements, resolved through the Manager that made them
+// Unlike EntAddr/EleAddr they are plain Copy data, so they're cheap to store, hash and send between threads
+// A slot's generation is bumped when its entity or element is destroyed, so stale handles resolve to nothing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct EntHandle {
+    key: SlotKey
+}
+
+impl EntHandle {
+    pub(crate) fn new(key: SlotKey) -> Self {
+        Self { key }
+    }
+    pub(crate) fn invalid() -> Self {
+        Self { key: SlotKey { owner: 0, index: u32::MAX, generation: 0 } }
+    }
+    pub(crate) fn key(&self) -> SlotKey {
+        self.key
+    }
+    pub fn index(&self) -> u32 {
+        self.key.index
+    }
+    pub fn generation(&self) -> u32 {
+        self.key.generation
+    }
+}
+
+pub struct EleHandle<T> {
+    key: SlotKey,
+    marker: PhantomData<fn() -> T>
+}
+
+impl<T> EleHandle<T> {
+    pub(crate) fn new(key: SlotKey) -> Self {
+        Self { key, marker: PhantomData }
+    }
+    pub(crate) fn key(&self) -> SlotKey {
+        self.key
+    }
+    pub fn index(&self) -> u32 {
+        self.key.index
+    }
+    pub fn generation(&self) -> u32 {
+        self.key.generation
+    }
+}
+
+// Implemented by hand, deriving would require T to implement them too
+impl<T> Clone for EleHandle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for EleHandle<T> { }
+impl<T> PartialEq for EleHandle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+impl<T> Eq for EleHandle<T> { }
+impl<T> Hash for EleHandle<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+    }
+}
+impl<T> fmt::Debug for EleHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EleHandle<{}>({}v{})", std::any::type_name::<T>(), self.key.index, self.key.generation)
+    }
+}
+
+// A slot of one Slots; owner tells the Slots apart so a handle from another Manager resolves to nothing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct SlotKey {
+    owner: u32,
+    index: u32,
+    generation: u32
+}
+
+// 0 is never an owner, it's left for invalid handles
+static NEXT_OWNER: AtomicU32 = AtomicU32::new(1);
+
+// Generational slots behind the handles; freed slots are reused with the next generation
+pub(crate) struct Slots<V> {
+    owner: u32,
+    entries: Vec<(u32, Option<V>)>,
+    free: Vec<u32>
+}
+
+impl<V> Default for Slots<V> {
+    fn default() -> Self {
+        Self { owner: NEXT_OWNER.fetch_add(1, Ordering::Relaxed), entries: Vec::new(), free: Vec::new() }
+    }
+}
+
+impl<V> Slots<V> {
+    // The key of the slot now holding val
+    pub(crate) fn insert(&mut self, val: V) -> SlotKey {
+        let (index, generation) = match self.free.pop() {
+            Some(index) => {
+                let entry = &mut self.entries[index as usize];
+                entry.1 = Some(val);
+                (index, entry.0)
+            },
+            None => {
+                self.entries.push((0, Some(val)));
+                (self.entries.len() as u32 - 1, 0)
+            }
+        };
+        SlotKey { owner: self.owner, index, generation }
+    }
+    pub(crate) fn remove(&mut self, key: SlotKey) -> Option<V> {
+        if key.owner != self.owner {
+            return None;
+        }
+        let (index, generation) = (key.index, key.generation);
+        let entry = self.entries.get_mut(index as usize).filter(|entry| entry.0 == generation)?;
+        let val = entry.1.take()?;
+        entry.0 = entry.0.wrapping_add(1);
+        self.free.push(index);
+        Some(val)
+    }
+    pub(crate) fn get(&self, key: SlotKey) -> Option<&V> {
+        if key.owner != self.owner {
+            return None;
+        }
+        self.entries.get(key.index as usize)
+        .filter(|entry| entry.0 == key.generation)
+        .and_then(|entry| entry.1.as_ref())
+    }
+}
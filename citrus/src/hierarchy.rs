@@ -0,0 +1,113 @@
+use std::collections::VecDeque;
+
+use crate::entity::*;
+
+// Hierarchy traversal iterators
+// Each iterator holds only addresses and reads an entity's children without borrowing it,
+// so the entities may be borrowed, even mutably, while iterating
+
+// Depth-first pre-order; an entity is yielded before its children
+pub struct DepthFirstIter {
+    stack: Vec<EntAddr>
+}
+
+impl DepthFirstIter {
+    pub fn new(mut roots: Vec<EntAddr>) -> Self {
+        roots.reverse();
+        Self { stack: roots }
+    }
+}
+
+impl Iterator for DepthFirstIter {
+    type Item = EntAddr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let curr = self.stack.pop()?;
+            let children = curr.peek_children();
+            if let Some(children) = children {
+                self.stack.extend(children.into_iter().rev());
+                return Some(curr);
+            }
+        }
+    }
+}
+
+// Depth-first post-order; an entity is yielded after all of its children
+pub struct PostOrderIter {
+    stack: Vec<(EntAddr, bool)>
+}
+
+impl PostOrderIter {
+    pub fn new(roots: Vec<EntAddr>) -> Self {
+        Self { stack: roots.into_iter().rev().map(|root| (root, false)).collect() }
+    }
+}
+
+impl Iterator for PostOrderIter {
+    type Item = EntAddr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (curr, expanded) = self.stack.pop()?;
+            if expanded {
+                return Some(curr);
+            }
+            let children = curr.peek_children();
+            if let Some(children) = children {
+                self.stack.push((curr, true));
+                self.stack.extend(children.into_iter().rev().map(|child| (child, false)));
+            }
+        }
+    }
+}
+
+// Breadth-first; all entities of one depth are yielded before the next depth
+pub struct BreadthFirstIter {
+    queue: VecDeque<EntAddr>
+}
+
+impl BreadthFirstIter {
+    pub fn new(roots: Vec<EntAddr>) -> Self {
+        Self { queue: roots.into() }
+    }
+}
+
+impl Iterator for BreadthFirstIter {
+    type Item = EntAddr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let curr = self.queue.pop_front()?;
+            let children = curr.peek_children();
+            if let Some(children) = children {
+                self.queue.extend(children);
+                return Some(curr);
+            }
+        }
+    }
+}
+
+// Walks from the parent of an entity up to its root, excluding the entity itself
+pub struct AncestorIter {
+    curr: EntAddr
+}
+
+impl AncestorIter {
+    pub fn new(start: EntAddr) -> Self {
+        Self { curr: start }
+    }
+}
+
+impl Iterator for AncestorIter {
+    type Item = EntAddr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let parent = self.curr.peek_parent()?;
+        self.curr = parent.clone();
+        match parent.valid() {
+            true => Some(parent),
+            false => None
+        }
+    }
+}
@@ -1,20 +1,38 @@
+pub mod borrow;
 pub mod element;
 pub mod entity;
+pub mod events;
+pub mod handles;
+pub mod hierarchy;
+#[cfg(feature = "sync")]
+pub mod parallel;
+pub mod query;
+pub mod resources;
 pub mod scene_serde;
+pub mod scene_patch;
+pub mod scene_history;
+pub mod scene_prefab;
+pub mod schedule;
+pub mod sync;
+pub mod time;
 #[cfg(feature = "gen-imgui")]
 pub mod editor_helpers;
 #[cfg(feature = "gen-imgui")]
 pub mod scene_editor;
 pub mod deserialize_context;
-
-#[macro_use]
-extern crate lazy_static;
+#[cfg(feature = "scene_lua")]
+pub mod scene_lua;
 
 #[cfg(test)]
 mod tests {
     use serde::*;
     use crate::element::*;
     use crate::entity::*;
+    use crate::query::*;
+    use crate::schedule::*;
+    use crate::scene_serde::*;
+    use crate::scene_patch::*;
+    use crate::scene_history::*;
 
     #[derive(Clone, Serialize, Deserialize)]
     pub struct PosRot {
@@ -56,6 +74,18 @@ mod tests {
 
     impl Element for B { }
 
+    #[derive(Clone, Serialize, Deserialize)]
+    struct Collider {
+        radius: f32,
+        target: EleAddr<Collider>
+    }
+
+    impl Element for Collider {
+        fn multi_instance() -> bool {
+            true
+        }
+    }
+
     #[test]
     fn test_ecs() {
         let eh = EntityHolder::new("test entity".to_string());
@@ -100,4 +130,1214 @@ mod tests {
 
         assert!(m.of_type::<A>().len() == 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_hierarchy_traversal() {
+        let mut m = Manager::new();
+        let root = m.create_entity("root".to_string());
+        let a = m.create_entity("a".to_string());
+        let b = m.create_entity("b".to_string());
+        let a0 = m.create_entity("a0".to_string());
+        let other = m.create_entity("other".to_string());
+        m.reparent(a.clone(), root.clone()).unwrap();
+        m.reparent(b.clone(), root.clone()).unwrap();
+        m.reparent(a0.clone(), a.clone()).unwrap();
+
+        let names = |iter: &mut dyn Iterator<Item = EntAddr>| -> Vec<String> {
+            iter.map(|ent| ent.get_ref().unwrap().name.clone()).collect()
+        };
+
+        assert_eq!(names(&mut root.iter_depth_first()), vec!["root", "a", "a0", "b"]);
+        assert_eq!(names(&mut root.iter_post_order()), vec!["a0", "a", "b", "root"]);
+        assert_eq!(names(&mut root.iter_breadth_first()), vec!["root", "a", "b", "a0"]);
+        assert_eq!(names(&mut a0.iter_ancestors()), vec!["a", "root"]);
+        assert_eq!(names(&mut root.get_ref().unwrap().get_all_children().into_iter()), vec!["a", "a0", "b"]);
+        assert_eq!(names(&mut m.iter_depth_first()), vec!["root", "a", "a0", "b", "other"]);
+        assert!(m.siblings(&a) == vec![b.clone()]);
+        assert!(m.siblings(&other) == vec![root.clone()]);
+
+        // mutably borrowed entities are still visited, along with their subtrees
+        {
+            let _held = a.get_ref_mut().unwrap();
+            assert!(root.iter_depth_first().collect::<Vec<EntAddr>>() == [root.clone(), a.clone(), a0.clone(), b.clone()]);
+            assert!(root.iter_post_order().count() == 4 && root.iter_breadth_first().count() == 4);
+            assert!(a0.iter_ancestors().collect::<Vec<EntAddr>>() == [a.clone(), root.clone()]);
+            assert!(m.siblings(&a) == vec![b.clone()]);
+        }
+    }
+
+    #[test]
+    fn test_manager_multi_query() {
+        let mut m = Manager::new();
+        let ab = m.create_entity("ab".to_string());
+        let a = m.create_entity("a".to_string());
+        let b = m.create_entity("b".to_string());
+        ab.get_ref_mut().unwrap().add_element(A { val: 1 }).unwrap();
+        ab.get_ref_mut().unwrap().add_element(B { bal: 2 }).unwrap();
+        a.get_ref_mut().unwrap().add_element(A { val: 3 }).unwrap();
+        b.get_ref_mut().unwrap().add_element(B { bal: 4 }).unwrap();
+
+        assert!(m.query::<(A, B)>().len() == 1);
+        assert!(m.query::<(A, With<B>)>()[0].0 == ab);
+        assert!(m.query::<(A, Without<B>)>()[0].0 == a);
+
+        let optional = m.query::<(A, Option<B>)>();
+        assert!(optional.len() == 2);
+        assert!(optional.iter().filter(|(_, (_, b))| b.is_some()).count() == 1);
+
+        m.query_each::<(A, Option<B>), _>(|_, (mut a, b)| {
+            a.val += b.map_or(0, |b| b.bal);
+        });
+        assert!(ab.get_ref_mut().unwrap().query_element::<A>().unwrap().val == 3);
+        assert!(a.get_ref_mut().unwrap().query_element::<A>().unwrap().val == 3);
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    struct Flock {
+        others: usize
+    }
+
+    impl Element for Flock {
+        fn update(&mut self, man: &mut Manager, _owner: EntAddr) {
+            // the running instance is borrowed and gets skipped
+            let mut others = 0;
+            man.query_each::<Flock, _>(|_, _| others += 1);
+            self.others = others;
+        }
+    }
+
+    #[test]
+    fn test_query_inside_update() {
+        let mut m = Manager::new();
+        let flock = (0..3)
+            .map(|i| m.create_entity(format!("bird {}", i)).get_ref_mut().unwrap().add_element(Flock { others: 0 }).unwrap())
+            .collect::<Vec<EleAddr<Flock>>>();
+        m.update(0.1);
+        assert!(flock.iter().all(|bird| bird.get_ref().unwrap().others == 2));
+    }
+
+    #[test]
+    fn test_element_index() {
+        let mut m = Manager::new();
+        let parent = m.create_entity("parent".to_string());
+        let child = m.create_entity("child".to_string());
+        let other = m.create_entity("other".to_string());
+        m.reparent(child.clone(), parent.clone()).unwrap();
+        parent.get_ref_mut().unwrap().add_element(A { val: 1 }).unwrap();
+        child.get_ref_mut().unwrap().add_element(A { val: 2 }).unwrap();
+        let other_a = other.get_ref_mut().unwrap().add_element(A { val: 3 }).unwrap();
+        other.get_ref_mut().unwrap().add_element(B { bal: 4 }).unwrap();
+
+        assert!(m.of_type::<A>().len() == 3);
+        assert!(m.of_type::<B>().len() == 1);
+
+        m.destroy_element(other_a.into());
+        m.resolve();
+        assert!(m.of_type::<A>().len() == 2);
+        assert!(m.query::<(A, B)>().is_empty());
+
+        // destroying the parent also destroys the child and both of their elements
+        m.destroy_entity(parent);
+        m.resolve();
+        assert!(m.of_type::<A>().is_empty());
+        assert!(m.of_type::<B>()[0].get_owner() == other);
+
+        // removing from the middle moves the last element into the gap
+        let many = (0..5)
+            .map(|i| m.create_entity(format!("many {}", i)).get_ref_mut().unwrap().add_element(A { val: i }).unwrap())
+            .collect::<Vec<EleAddr<A>>>();
+        m.destroy_element(many[1].clone().into());
+        m.destroy_element(many[4].clone().into());
+        m.resolve();
+        m.destroy_element(many[0].clone().into());
+        m.resolve();
+        let mut vals = m.of_type::<A>().iter().map(|a| a.get_ref().unwrap().val).collect::<Vec<i32>>();
+        vals.sort();
+        assert!(vals == [2, 3]);
+    }
+
+    fn make_mesh_scene() -> (SceneSerde, serde_json::Value) {
+        let mut m = Manager::new();
+        let mut scene = SceneSerde::new();
+        scene.register_element_creator(PosRot { pos: [0.0; 3] }, "PosRot");
+        scene.register_element_creator(Mesh { pos: EleAddr::new() }, "Mesh");
+
+        let ent = m.create_entity("mesh".to_string());
+        let pos = ent.get_ref_mut().unwrap().add_element(PosRot { pos: [1.0, 2.0, 3.0] }).unwrap();
+        ent.get_ref_mut().unwrap().add_element(Mesh { pos }).unwrap();
+
+        let all = m.all_entities();
+        let json = scene.serialize_scene(&mut m, all);
+        (scene, json)
+    }
+
+    fn load_mesh_scene(scene: &mut SceneSerde, json: serde_json::Value) {
+        let mut m = Manager::new();
+        let res = scene.deserialize_scene(&mut m, json).unwrap();
+        assert!(res.errors.is_empty());
+
+        let mesh = m.of_type::<Mesh>()[0].clone();
+        let pos = mesh.get_ref().unwrap().pos.clone();
+        assert!(pos.get_ref().unwrap().pos == [1.0, 2.0, 3.0]);
+        assert!(pos.get_owner() == mesh.get_owner());
+    }
+
+    #[test]
+    fn test_deserialize_independent_contexts() {
+        // EntAddr and EleAddr can't be deserialized outside of a scene load
+        assert!(serde_json::from_value::<EntAddr>(serde_json::json!(1)).is_err());
+
+        let (mut scene, json) = make_mesh_scene();
+        load_mesh_scene(&mut scene, json.clone());
+        load_mesh_scene(&mut scene, json.clone());
+
+        let threads: Vec<_> = (0..4).map(|_| {
+            let json = json.to_string();
+            std::thread::spawn(move || {
+                let (mut scene, _) = make_mesh_scene();
+                load_mesh_scene(&mut scene, serde_json::from_str(&json).unwrap());
+            })
+        }).collect();
+        threads.into_iter().for_each(|thread| thread.join().unwrap());
+    }
+
+    #[test]
+    fn test_scene_ids_round_trip() {
+        let (mut scene, json) = make_mesh_scene();
+        assert!(json["version"] == serde_json::json!(SCENE_FORMAT_VERSION));
+        let saved_id = json["entities"][0]["id"].as_str().unwrap().to_string();
+
+        let mut m = Manager::new();
+        let ents = scene.deserialize_scene(&mut m, json.clone()).unwrap().ents;
+        assert!(ents[0].get_ref().unwrap().get_id().to_string() == saved_id);
+        assert!(scene.serialize_scene(&mut m, ents) == json);
+
+        // loading the same scene again into the same Manager can't reuse the ids
+        let ents = scene.deserialize_scene(&mut m, json).unwrap().ents;
+        assert!(ents[0].get_ref().unwrap().get_id().to_string() != saved_id);
+        assert!(m.of_type::<Mesh>().len() == 2);
+    }
+
+    #[test]
+    fn test_legacy_scene_format() {
+        let legacy = serde_json::json!([
+            { "name": "parent", "parent_payload": 0, "id": -5, "eles": [] },
+            { "name": "mesh", "parent_payload": -5, "id": 7, "eles": [
+                { "name": "PosRot", "payload": { "pos": [1.0, 2.0, 3.0] } },
+                { "name": "Mesh", "payload": { "pos": { "ent_id": 7 } } }
+            ] }
+        ]);
+
+        let (mut scene, _) = make_mesh_scene();
+        let mut m = Manager::new();
+        let res = scene.deserialize_scene(&mut m, legacy).unwrap();
+        assert!(res.errors.is_empty());
+        assert!(res.ents[1].get_ref().unwrap().get_parent() == res.ents[0]);
+
+        let mesh = m.of_type::<Mesh>()[0].clone();
+        assert!(mesh.get_ref().unwrap().pos.get_ref().unwrap().pos == [1.0, 2.0, 3.0]);
+
+        let future = serde_json::json!({ "version": SCENE_FORMAT_VERSION + 1, "entities": [] });
+        assert!(matches!(scene.deserialize_scene(&mut m, future), Err(SceneSerdeError::VersionError(_))));
+    }
+
+    #[cfg(feature = "scene_lua")]
+    #[test]
+    fn test_lua_script() {
+        use crate::scene_lua::*;
+
+        let mut m = Manager::new();
+        let ent = m.create_entity("scripted".to_string());
+        let a = ent.get_ref_mut().unwrap().add_element(A { val: 1 }).unwrap();
+        let script = ent.get_ref_mut().unwrap().add_element(LuaScript::inline(r#"
+            function update(self)
+                local a = citrus.get_element(self, "A")
+                a.val = a.val * 2
+                citrus.set_element(self, "citrus_ecs::tests::A", a)
+                if #citrus.get_children(self) == 0 then
+                    local child = citrus.create_entity("spawned")
+                    citrus.reparent(child, self)
+                    assert(citrus.get_path(child) == "scripted/spawned")
+                    assert(citrus.find_by_path("..", child) == self)
+                end
+            end
+        "#)).unwrap();
+
+        m.update(1.0 / 60.0);
+        assert!(script.get_ref().unwrap().last_error().is_none());
+        // A updates itself before the script runs
+        assert!(a.get_ref().unwrap().val == 22);
+        let children = ent.get_ref().unwrap().get_children();
+        assert!(children.len() == 1 && children[0].get_ref().unwrap().name == "spawned");
+
+        let broken = m.create_entity("broken".to_string());
+        let script = broken.get_ref_mut().unwrap().add_element(LuaScript::inline("function update(self) citrus.destroy_entity('nope') end")).unwrap();
+        m.update(1.0 / 60.0);
+        assert!(script.get_ref().unwrap().last_error().unwrap().contains("nope"));
+
+        // the running script's own element is borrowed, which is an error rather than a panic
+        let own = m.create_entity("own".to_string());
+        let script = own.get_ref_mut().unwrap().add_element(LuaScript::inline("function update(self) citrus.get_element(self, 'LuaScript') end")).unwrap();
+        m.update(1.0 / 60.0);
+        assert!(script.get_ref().unwrap().last_error().unwrap().contains("borrowed"));
+    }
+
+    #[test]
+    fn test_scene_patch() {
+        let mut scene = SceneSerde::new();
+        scene.register_element_creator(A { val: 0 }, "A");
+        scene.register_element_creator(B { bal: 0 }, "B");
+
+        let mut m = Manager::new();
+        let kept = m.create_entity("kept".to_string());
+        let removed = m.create_entity("removed".to_string());
+        let mut a = kept.get_ref_mut().unwrap().add_element(A { val: 1 }).unwrap();
+        removed.get_ref_mut().unwrap().add_element(B { bal: 1 }).unwrap();
+        let all = m.all_entities();
+        let before = scene.serialize_scene(&mut m, all);
+
+        // the Manager that the patch is applied to starts out as a copy of before
+        let mut patched = Manager::new();
+        scene.deserialize_scene(&mut patched, before.clone()).unwrap();
+
+        kept.get_ref_mut().unwrap().name = "renamed".to_string();
+        a.get_ref_mut().unwrap().val = 5;
+        kept.get_ref_mut().unwrap().add_element(B { bal: 2 }).unwrap();
+        let added = m.create_entity("added".to_string());
+        m.reparent(added, kept.clone()).unwrap();
+        m.destroy_entity(removed);
+        m.resolve();
+        let all = m.all_entities();
+        let after = scene.serialize_scene(&mut m, all);
+
+        let patch = SceneSerde::diff(&before, &after).unwrap();
+        assert!(patch.entities.len() == 3);
+        let round_trip: ScenePatch = serde_json::from_value(serde_json::to_value(&patch).unwrap()).unwrap();
+        assert!(round_trip == patch);
+
+        let res = scene.apply_patch(&mut patched, &patch).unwrap();
+        assert!(res.errors.is_empty() && res.ents.len() == 1);
+        let all = patched.all_entities();
+        assert!(scene.serialize_scene(&mut patched, all) == after);
+
+        let res = scene.apply_patch(&mut patched, &patch.inverse()).unwrap();
+        assert!(res.errors.is_empty());
+        let all = patched.all_entities();
+        let reverted = scene.serialize_scene(&mut patched, all);
+        assert!(SceneSerde::diff(&before, &reverted).unwrap().is_empty());
+
+        // a field path that isn't a JSON pointer is reported rather than applied
+        let fields = vec![FieldChange { path: "val".to_string(), before: None, after: Some(serde_json::json!(3)) }];
+        let bad = ScenePatch::entity_modified(&kept, None, None, vec![ElementChange::Modified { name: "A".to_string(), instance: 0, fields }]);
+        let id = kept.get_ref().unwrap().get_id();
+        let res = scene.apply_patch(&mut patched, &bad).unwrap();
+        assert!(res.errors.len() == 1);
+        assert!(patched.find_by_id(id).get_ref_mut().unwrap().query_element::<A>().unwrap().val == 1);
+
+        // the patch no longer fits once it has been undone
+        assert!(matches!(scene.apply_patch(&mut patched, &patch.inverse()), Err(SceneSerdeError::PatchError(_))));
+
+        // a patch that creates a cycle leaves the hierarchy as it was
+        let mut m = Manager::new();
+        let x = m.create_entity("x".to_string());
+        let y = m.create_entity("y".to_string());
+        let z = m.create_entity("z".to_string());
+        m.reparent(y.clone(), x.clone()).unwrap();
+        let added_id = uuid::Uuid::new_v4().to_string();
+        let snapshot = EntitySnapshot { name: "n".to_string(), parent: Some(y.get_ref().unwrap().get_id().to_string()), eles: vec![], active: true, disabled: vec![], tags: vec![], layers: 1 };
+        let mut patch = ScenePatch::entity_modified(&z, None, Some((EntAddr::new(), x.clone())), vec![]);
+        patch.entities.push(EntityChange::Added { id: added_id.clone(), entity: snapshot });
+        let x_id = x.get_ref().unwrap().get_id().to_string();
+        patch.entities.push(EntityChange::Modified { id: x_id, name: None, parent: Some((None, Some(added_id.clone()))), elements: vec![], active: None, tags: None, layers: None });
+        assert!(matches!(scene.apply_patch(&mut m, &patch), Err(SceneSerdeError::CycleError(_))));
+        assert!(m.all_entities().len() == 3 && !m.find_by_id(uuid::Uuid::parse_str(&added_id).unwrap()).valid());
+        assert!(!z.get_ref().unwrap().get_parent().valid() && !x.get_ref().unwrap().get_parent().valid());
+        assert!(y.get_ref().unwrap().get_parent() == x);
+
+        // so does one with a missing parent or an entity added twice
+        let missing = Some((None, Some(uuid::Uuid::new_v4().to_string())));
+        let mut orphaned = patch.clone();
+        if let EntityChange::Modified { parent, .. } = &mut orphaned.entities[2] {
+            *parent = missing;
+        }
+        let mut doubled = patch.clone();
+        doubled.entities.insert(2, doubled.entities[1].clone());
+        for bad in [orphaned, doubled] {
+            assert!(matches!(scene.apply_patch(&mut m, &bad), Err(SceneSerdeError::PatchError(_))));
+            assert!(m.all_entities().len() == 3 && !z.get_ref().unwrap().get_parent().valid());
+        }
+    }
+
+    #[test]
+    fn test_scene_history() {
+        let mut scene = SceneSerde::new();
+        scene.register_element_creator(A { val: 0 }, "A");
+        let mut history = SceneHistory::new();
+        let mut m = Manager::new();
+
+        let parent = m.create_entity("parent".to_string());
+        let child = m.create_entity("child".to_string());
+        m.reparent(child.clone(), parent.clone()).unwrap();
+        child.get_ref_mut().unwrap().add_element(A { val: 7 }).unwrap();
+        history.push("Create entities", ScenePatch::entities_added(&mut scene, &[parent.clone(), child.clone()]), None);
+        let child_id = child.get_ref().unwrap().get_id();
+
+        // consecutive renames merge into a single entry
+        for name in ["p", "pa"] {
+            let before = parent.get_ref().unwrap().name.clone();
+            parent.get_ref_mut().unwrap().name = name.to_string();
+            history.push("Rename", ScenePatch::entity_modified(&parent, Some((before, name.to_string())), None, vec![]), Some("name".to_string()));
+        }
+        assert!(history.undo_entries().len() == 2);
+
+        let subtree = parent.iter_depth_first().collect::<Vec<EntAddr>>();
+        history.push("Destroy", ScenePatch::entities_removed(&mut scene, &subtree), None);
+        m.destroy_entity(parent);
+        m.resolve();
+        assert!(m.all_entities().is_empty());
+
+        // the destroyed subtree comes back with its original ids, name and elements
+        assert!(history.undo(&mut scene, &mut m).unwrap() == Some("Destroy".to_string()));
+        let child = m.find_by_id(child_id);
+        let parent = child.get_ref().unwrap().get_parent();
+        assert!(parent.get_ref().unwrap().name == "pa");
+        assert!(child.get_ref_mut().unwrap().query_element::<A>().unwrap().val == 7);
+
+        history.undo(&mut scene, &mut m).unwrap();
+        assert!(parent.get_ref().unwrap().name == "parent");
+        history.undo(&mut scene, &mut m).unwrap();
+        assert!(m.all_entities().is_empty() && !history.can_undo());
+
+        while history.can_redo() {
+            history.redo(&mut scene, &mut m).unwrap();
+        }
+        assert!(m.all_entities().is_empty() && history.undo_entries().len() == 3);
+    }
+
+    #[test]
+    fn test_prefab_instantiate() {
+        let mut scene = SceneSerde::new();
+        scene.register_element_creator(PosRot { pos: [0.0; 3] }, "PosRot");
+        scene.register_element_creator(Mesh { pos: EleAddr::new() }, "Mesh");
+        let mut m = Manager::new();
+
+        let world = m.create_entity("world".to_string());
+        let world_pos = world.get_ref_mut().unwrap().add_element(PosRot { pos: [9.0; 3] }).unwrap();
+
+        let root = m.create_entity("root".to_string());
+        let child = m.create_entity("child".to_string());
+        let outside = m.create_entity("outside".to_string());
+        m.reparent(child.clone(), root.clone()).unwrap();
+        let root_pos = root.get_ref_mut().unwrap().add_element(PosRot { pos: [1.0; 3] }).unwrap();
+        child.get_ref_mut().unwrap().add_element(Mesh { pos: root_pos }).unwrap();
+        outside.get_ref_mut().unwrap().add_element(Mesh { pos: world_pos.clone() }).unwrap();
+        m.reparent(outside.clone(), root.clone()).unwrap();
+
+        assert!(scene.create_prefab(&mut m, EntAddr::new()).is_none());
+        let prefab = scene.create_prefab(&mut m, root.clone()).unwrap();
+        let holder = m.create_entity("holder".to_string());
+        let first = scene.instantiate_prefab(&mut m, &prefab, holder.clone()).unwrap();
+        let second = scene.instantiate_prefab(&mut m, &prefab, EntAddr::new()).unwrap();
+
+        assert!(first.get_ref().unwrap().get_parent() == holder);
+        assert!(!second.get_ref().unwrap().get_parent().valid());
+        assert!(first.get_ref().unwrap().get_id() != root.get_ref().unwrap().get_id());
+        assert!(first.get_ref().unwrap().get_id() != second.get_ref().unwrap().get_id());
+
+        for inst in [first, second] {
+            let children = inst.get_ref().unwrap().get_children();
+            assert!(children.len() == 2);
+            // references inside the prefab point to the new copies, references out of it are kept
+            let mesh = children[0].get_ref_mut().unwrap().query_element_addr::<Mesh>();
+            assert!(mesh.get_ref().unwrap().pos.get_owner() == inst);
+            let mesh = children[1].get_ref_mut().unwrap().query_element_addr::<Mesh>();
+            assert!(mesh.get_ref().unwrap().pos.get_owner() == world);
+        }
+        assert!(m.all_entities().len() == 11);
+    }
+
+    #[test]
+    fn test_duplicate_entity() {
+        let mut m = Manager::new();
+        let world = m.create_entity("world".to_string());
+        let world_pos = world.get_ref_mut().unwrap().add_element(PosRot { pos: [9.0; 3] }).unwrap();
+
+        let prop = m.create_entity("prop".to_string());
+        let part = m.create_entity("part".to_string());
+        m.reparent(prop.clone(), world.clone()).unwrap();
+        m.reparent(part.clone(), prop.clone()).unwrap();
+        let prop_pos = prop.get_ref_mut().unwrap().add_element(PosRot { pos: [1.0; 3] }).unwrap();
+        prop.get_ref_mut().unwrap().add_element(Mesh { pos: world_pos }).unwrap();
+        part.get_ref_mut().unwrap().add_element(Mesh { pos: prop_pos }).unwrap();
+
+        let shallow = m.duplicate_entity(prop.clone(), false);
+        assert!(shallow.get_ref().unwrap().get_children().is_empty());
+        assert!(shallow.get_ref().unwrap().get_parent() == world);
+
+        let copy = m.duplicate_entity(prop.clone(), true);
+        assert!(copy.get_ref().unwrap().name == "prop");
+        assert!(copy.get_ref().unwrap().get_id() != prop.get_ref().unwrap().get_id());
+        assert!(copy.get_ref().unwrap().get_parent() == world);
+        assert!(copy.get_ref_mut().unwrap().query_element::<PosRot>().unwrap().pos == [1.0; 3]);
+
+        // references into the copied subtree are remapped, others are kept
+        let copy_mesh = copy.get_ref_mut().unwrap().query_element_addr::<Mesh>();
+        assert!(copy_mesh.get_ref().unwrap().pos.get_owner() == world);
+        let copy_part = copy.get_ref().unwrap().get_children()[0].clone();
+        assert!(copy_part != part);
+        let part_mesh = copy_part.get_ref_mut().unwrap().query_element_addr::<Mesh>();
+        assert!(part_mesh.get_ref().unwrap().pos.get_owner() == copy);
+        assert!(m.of_type::<Mesh>().len() == 5);
+    }
+
+    #[test]
+    fn test_multi_instance_elements() {
+        let mut scene = SceneSerde::new();
+        scene.register_element_creator(Collider { radius: 0.0, target: EleAddr::new() }, "Collider");
+        scene.register_element_creator(A { val: 0 }, "A");
+        let mut m = Manager::new();
+
+        let ent = m.create_entity("ent".to_string());
+        let first = ent.get_ref_mut().unwrap().add_element(Collider { radius: 1.0, target: EleAddr::new() }).unwrap();
+        let second = ent.get_ref_mut().unwrap().add_element(Collider { radius: 2.0, target: EleAddr::new() }).unwrap();
+        ent.get_ref_mut().unwrap().add_element(Collider { radius: 3.0, target: second.clone() }).unwrap();
+        ent.get_ref_mut().unwrap().add_element(A { val: 1 }).unwrap();
+        assert!(ent.get_ref_mut().unwrap().add_element(A { val: 2 }).is_err());
+        assert!(second.instance_index() == Some(1));
+        assert!(ent.get_ref_mut().unwrap().query_element::<Collider>().unwrap().radius == 1.0);
+
+        // instance order and references to a particular instance survive a round trip
+        let all = m.all_entities();
+        let json = scene.serialize_scene(&mut m, all);
+        let mut loaded = Manager::new();
+        let res = scene.deserialize_scene(&mut loaded, json.clone()).unwrap();
+        assert!(res.errors.is_empty());
+        let colliders = res.ents[0].get_ref_mut().unwrap().query_elements::<Collider>();
+        assert!(colliders.iter().map(|c| c.get_ref().unwrap().radius).collect::<Vec<f32>>() == vec![1.0, 2.0, 3.0]);
+        assert!(colliders[2].get_ref().unwrap().target.instance_index() == Some(1));
+
+        // removing the first instance and undoing it puts it back in its place
+        let payload = first.get_ref().unwrap().ecs_serialize();
+        let change = ElementChange::Removed { name: "Collider".to_string(), instance: 0, payload, enabled: true };
+        let patch = ScenePatch::entity_modified(&ent, None, None, vec![change]);
+        scene.apply_patch(&mut m, &patch).unwrap();
+        assert!(!first.valid() && second.instance_index() == Some(0));
+        scene.apply_patch(&mut m, &patch.inverse()).unwrap();
+        let all = m.all_entities();
+        assert!(SceneSerde::diff(&json, &scene.serialize_scene(&mut m, all)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_schedule() {
+        use std::sync::{Arc, Mutex};
+
+        // systems have to be Send + Sync with the sync feature
+        let mut m = Manager::new();
+        let log = Arc::new(Mutex::new(Vec::<String>::new()));
+        let logger = |name: &'static str| {
+            let log = log.clone();
+            move |_: &mut Manager| log.lock().unwrap().push(name.to_string())
+        };
+
+        m.add_system(Stage::Update, "physics", logger("physics")).unwrap().after("input");
+        m.add_system(Stage::Update, "input", logger("input")).unwrap();
+        m.add_system(Stage::Render, "render", logger("render")).unwrap();
+        m.add_system(Stage::PreUpdate, "pre", logger("pre")).unwrap();
+        m.add_system(Stage::Update, "animation", logger("animation")).unwrap().before("input");
+        assert!(m.add_system(Stage::Update, "input", logger("input")).is_err());
+
+        // destroys and deferred changes made during a stage show up once the whole stage has run
+        let ent = m.create_entity("ent".to_string());
+        let doomed = m.create_entity("doomed".to_string());
+        m.add_system(Stage::PreUpdate, "spawner", move |man: &mut Manager| {
+            let ent = ent.clone();
+            man.defer(move |_| { ent.get_ref_mut().unwrap().add_element(B { bal: 0 }).unwrap(); });
+            man.destroy_entity(doomed.clone());
+        }).unwrap();
+        let check_log = log.clone();
+        m.add_system(Stage::PreUpdate, "checker", move |man: &mut Manager| {
+            check_log.lock().unwrap().push(format!("{} {}", man.of_type::<B>().len(), man.all_entities().len()));
+        }).unwrap();
+
+        m.update(1.0 / 60.0);
+        assert!(*log.lock().unwrap() == ["pre", "0 2", "animation", "input", "physics", "render"]);
+        assert!(m.of_type::<B>().len() == 1 && m.all_entities().len() == 1);
+        assert!(m.schedule_mut().remove_system("spawner") && m.schedule_mut().remove_system("checker"));
+
+        m.schedule_mut().get_system("render").unwrap().before("physics");
+        assert!(m.schedule().stage_order(Stage::Update).unwrap() == ["animation", "input", "physics"]);
+        m.add_system(Stage::PostUpdate, "a", logger("a")).unwrap().before("b");
+        m.add_system(Stage::PostUpdate, "b", logger("b")).unwrap().before("a");
+        assert!(m.schedule().stage_order(Stage::PostUpdate).is_err());
+
+        // a cycle doesn't stop the stage, its systems run last in the order they were added
+        log.lock().unwrap().clear();
+        m.update(1.0 / 60.0);
+        assert!(log.lock().unwrap().ends_with(&["a".to_string(), "b".to_string(), "render".to_string()]));
+        assert!(m.schedule().cycle(Stage::PostUpdate).unwrap().contains("a, b"));
+        assert!(m.schedule().cycle(Stage::Update).is_none());
+
+        // systems can edit the schedule, which takes effect once the stage is done
+        let edit_log = log.clone();
+        m.add_system(Stage::PreUpdate, "editor", move |man: &mut Manager| {
+            let edit_log = edit_log.clone();
+            let schedule = man.schedule_mut();
+            assert!(schedule.remove_system("a") && schedule.get_system("a").is_none());
+            schedule.add_system(Stage::PreUpdate, "added", move |_: &mut Manager| edit_log.lock().unwrap().push("added".to_string())).unwrap();
+            assert!(schedule.get_system("added").is_some() && schedule.add_system(Stage::PreUpdate, "added", |_: &mut Manager| {}).is_err());
+            assert!(schedule.remove_system("editor"));
+        }).unwrap();
+        log.lock().unwrap().clear();
+        m.update(1.0 / 60.0);
+        assert!(!log.lock().unwrap().contains(&"added".to_string()) && !log.lock().unwrap().contains(&"a".to_string()));
+        assert!(m.schedule().systems().iter().all(|entry| entry.name() != "editor" && entry.name() != "a"));
+        log.lock().unwrap().clear();
+        m.update(1.0 / 60.0);
+        assert!(log.lock().unwrap()[0] == "pre" && log.lock().unwrap().contains(&"added".to_string()));
+    }
+
+    #[test]
+    fn test_update_order() {
+        use std::{any::TypeId, cell::RefCell};
+
+        thread_local! {
+            static LOG: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+        }
+        fn log(kind: &str, owner: &EntAddr) {
+            LOG.with(|log| log.borrow_mut().push(format!("{} {}", kind, owner.get_ref().unwrap().name)));
+        }
+
+        #[derive(Clone, Serialize, Deserialize)]
+        struct Early;
+        impl Element for Early {
+            fn update_priority() -> i32 {
+                -1
+            }
+            fn update(&mut self, _man: &mut Manager, owner: EntAddr) {
+                log("early", &owner);
+            }
+        }
+        #[derive(Clone, Serialize, Deserialize)]
+        struct Mid;
+        impl Element for Mid {
+            fn update(&mut self, _man: &mut Manager, owner: EntAddr) {
+                log("mid", &owner);
+            }
+        }
+        #[derive(Clone, Serialize, Deserialize)]
+        struct Late;
+        impl Element for Late {
+            fn update_after() -> Vec<TypeId> {
+                vec![TypeId::of::<Mid>()]
+            }
+            fn update(&mut self, _man: &mut Manager, owner: EntAddr) {
+                log("late", &owner);
+            }
+        }
+
+        let mut m = Manager::new();
+        let child = m.create_entity("child".to_string());
+        let parent = m.create_entity("parent".to_string());
+        m.reparent(child.clone(), parent.clone()).unwrap();
+        child.get_ref_mut().unwrap().add_element(Late).unwrap();
+        child.get_ref_mut().unwrap().add_element(Mid).unwrap();
+        child.get_ref_mut().unwrap().add_element(Early).unwrap();
+        parent.get_ref_mut().unwrap().add_element(Mid).unwrap();
+
+        m.update(1.0 / 60.0);
+        assert!(LOG.with(|log| log.take()) == ["early child", "mid child", "mid parent", "late child"]);
+
+        m.set_update_order(UpdateOrder::Hierarchy);
+        m.update(1.0 / 60.0);
+        assert!(LOG.with(|log| log.take()) == ["early child", "mid parent", "mid child", "late child"]);
+
+        // a cycle is broken by ignoring the update_after of the type first by name, and reported until it's gone
+        #[derive(Clone, Serialize, Deserialize)]
+        struct Ping;
+        impl Element for Ping {
+            fn update_after() -> Vec<TypeId> {
+                vec![TypeId::of::<Pong>()]
+            }
+            fn update(&mut self, _man: &mut Manager, owner: EntAddr) {
+                log("ping", &owner);
+            }
+        }
+        #[derive(Clone, Serialize, Deserialize)]
+        struct Pong;
+        impl Element for Pong {
+            fn update_after() -> Vec<TypeId> {
+                vec![TypeId::of::<Ping>()]
+            }
+            fn update(&mut self, _man: &mut Manager, owner: EntAddr) {
+                log("pong", &owner);
+            }
+        }
+
+        let looped = m.create_entity("looped".to_string());
+        looped.get_ref_mut().unwrap().add_element(Pong).unwrap();
+        looped.get_ref_mut().unwrap().add_element(Ping).unwrap();
+        m.update(1.0 / 60.0);
+        let looped_log = LOG.with(|log| log.take()).into_iter().filter(|line| line.ends_with("looped")).collect::<Vec<String>>();
+        assert!(looped_log == ["ping looped", "pong looped"]);
+        assert!(m.update_cycle().unwrap().ends_with(&format!("ignoring those of {}", std::any::type_name::<Ping>())));
+
+        m.destroy_entity(looped);
+        m.update(1.0 / 60.0);
+        assert!(m.update_cycle().is_none());
+    }
+
+    #[test]
+    fn test_events() {
+        use std::any::{Any, TypeId};
+
+        struct Damage(i32);
+
+        #[derive(Clone, Serialize, Deserialize)]
+        struct Health {
+            hp: i32
+        }
+        impl Element for Health {
+            fn event_types() -> Vec<TypeId> {
+                vec![TypeId::of::<Damage>()]
+            }
+            fn on_event(&mut self, _man: &mut Manager, _owner: EntAddr, event: &dyn Any) {
+                if let Some(damage) = event.downcast_ref::<Damage>() {
+                    self.hp -= damage.0;
+                }
+            }
+        }
+
+        let mut m = Manager::new();
+        let first = m.create_entity("first".to_string());
+        let second = m.create_entity("second".to_string());
+        let first_hp = first.get_ref_mut().unwrap().add_element(Health { hp: 100 }).unwrap();
+        let second_hp = second.get_ref_mut().unwrap().add_element(Health { hp: 100 }).unwrap();
+
+        m.send(Damage(10));
+        m.send_to(first.clone(), Damage(5));
+        // nothing is readable until the next update
+        assert!(m.read_events::<Damage>().is_empty());
+
+        m.update(1.0 / 60.0);
+        assert!(first_hp.get_ref().unwrap().hp == 85 && second_hp.get_ref().unwrap().hp == 90);
+        assert!(m.read_events::<Damage>().len() == 2);
+        assert!(m.read_events_for::<Damage>(&second).len() == 1);
+        assert!(m.read_events::<i32>().is_empty());
+
+        m.update(1.0 / 60.0);
+        assert!(m.read_events::<Damage>().is_empty());
+        assert!(first_hp.get_ref().unwrap().hp == 85);
+
+        // handlers are found again after every element of their type was gone
+        m.destroy_element(first_hp.into());
+        m.destroy_element(second_hp.into());
+        m.resolve();
+        let third_hp = second.get_ref_mut().unwrap().add_element(Health { hp: 50 }).unwrap();
+        m.send(Damage(20));
+        m.update(1.0 / 60.0);
+        assert!(third_hp.get_ref().unwrap().hp == 30);
+    }
+
+    #[test]
+    fn test_change_detection() {
+        let mut m = Manager::new();
+        let first = m.create_entity("first".to_string());
+        let second = m.create_entity("second".to_string());
+        let mut first_b = first.get_ref_mut().unwrap().add_element(B { bal: 1 }).unwrap();
+        let second_b = second.get_ref_mut().unwrap().add_element(B { bal: 2 }).unwrap();
+        m.resolve();
+        assert!(m.added::<B>().len() == 2 && m.changed::<B>().len() == 2);
+
+        // running the elements' hooks counts as a change
+        m.update(1.0 / 60.0);
+        assert!(m.added::<B>().is_empty() && m.changed::<B>().len() == 2);
+        m.clear_changes();
+        assert!(m.changed::<B>().is_empty());
+
+        // reading doesn't count as a change, writing does
+        assert!(second_b.get_ref().unwrap().bal == 2);
+        first_b.get_ref_mut().unwrap().bal += 1;
+        assert!(m.changed::<B>().len() == 1 && m.changed::<B>()[0].get_ref().unwrap().bal == 2);
+        second_b.mark_changed();
+        assert!(m.changed::<B>().len() == 2);
+
+        m.destroy_element(second_b.into());
+        m.destroy_entity(first.clone());
+        m.resolve();
+        let removed = m.removed::<B>();
+        assert!(removed.len() == 2 && removed.contains(&second.get_ref().unwrap().get_id()));
+        assert!(m.removed::<A>().is_empty() && m.changed::<B>().is_empty());
+
+        m.update(1.0 / 60.0);
+        assert!(m.removed::<B>().is_empty());
+    }
+
+    #[test]
+    fn test_try_borrow() {
+        use crate::borrow::*;
+
+        #[derive(Clone, Serialize, Deserialize)]
+        struct Reentrant {
+            err: Option<String>
+        }
+        impl Element for Reentrant {
+            fn update(&mut self, _man: &mut Manager, owner: EntAddr) {
+                let mut own = owner.get_ref_mut().unwrap().query_element_addr::<Reentrant>();
+                self.err = own.try_get_ref_mut().err().map(|err| err.to_string());
+            }
+        }
+
+        let mut m = Manager::new();
+        let ent = m.create_entity("ent".to_string());
+        let mut b = ent.get_ref_mut().unwrap().add_element(B { bal: 1 }).unwrap();
+        let mut erased: EleAddrErased = b.clone().into();
+
+        {
+            let _held = b.get_ref_mut().unwrap();
+            let err = b.try_get_ref().err().unwrap();
+            assert!(err == BorrowError::AlreadyMutablyBorrowed(std::any::type_name::<B>()));
+            assert!(erased.try_get_ref_mut().err().unwrap().type_name() == std::any::type_name::<B>());
+        }
+        {
+            let _held = b.get_ref().unwrap();
+            assert!(matches!(b.try_get_ref_mut(), Err(BorrowError::AlreadyBorrowed(_))));
+            assert!(b.try_get_ref().is_ok());
+        }
+        {
+            let _held = ent.get_ref_mut().unwrap();
+            assert!(matches!(ent.try_get_ref(), Err(BorrowError::AlreadyMutablyBorrowed(_))));
+        }
+
+        let re = ent.get_ref_mut().unwrap().add_element(Reentrant { err: None }).unwrap();
+        m.update(1.0 / 60.0);
+        assert!(re.get_ref().unwrap().err.as_ref().is_some_and(|err| err.contains("Reentrant")));
+
+        m.destroy_element(erased.clone());
+        m.resolve();
+        assert!(matches!(b.try_get_ref(), Err(BorrowError::Dead(_))));
+        assert!(b.get_ref().is_none() && erased.try_get_ref_mut().is_err());
+
+        // destroying a borrowed element waits for a resolve where it isn't borrowed
+        let mut held = ent.get_ref_mut().unwrap().add_element(B { bal: 3 }).unwrap();
+        m.destroy_element(held.clone().into());
+        {
+            let _held = held.get_ref_mut().unwrap();
+            m.resolve();
+        }
+        assert!(held.valid());
+        m.resolve();
+        assert!(!held.valid());
+    }
+
+    #[cfg(feature = "borrow-tracking")]
+    #[test]
+    fn test_borrow_tracking() {
+        let mut m = Manager::new();
+        let ent = m.create_entity("ent".to_string());
+        let mut b = ent.get_ref_mut().unwrap().add_element(B { bal: 1 }).unwrap();
+        assert!(m.outstanding_borrows().is_empty());
+
+        let held = b.get_ref_mut().unwrap();
+        let line = line!() - 1;
+        let borrows = m.outstanding_borrows();
+        assert!(borrows.len() == 1 && borrows[0].mutable && borrows[0].location.line() == line);
+        assert!(borrows[0].type_name == std::any::type_name::<B>());
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| { b.get_ref(); }));
+        let msg = res.err().unwrap().downcast::<String>().unwrap();
+        assert!(msg.contains("already borrowed mutably") && msg.contains(&format!("lib.rs:{}", line)));
+
+        drop(held);
+        assert!(m.outstanding_borrows().is_empty());
+    }
+
+    #[test]
+    fn test_handles() {
+        fn assert_send_sync<H: Copy + Send + Sync>(_: H) { }
+
+        let mut m = Manager::new();
+        let first = m.create_entity("first".to_string());
+        let b = first.get_ref_mut().unwrap().add_element(B { bal: 3 }).unwrap();
+        let ent_handle = first.get_ref().unwrap().get_handle();
+        let ele_handle = b.handle().unwrap();
+        assert_send_sync(ent_handle);
+        assert_send_sync(ele_handle);
+
+        assert!(m.entity(ent_handle) == first);
+        assert!(m.element(ele_handle).get_ref().unwrap().bal == 3);
+
+        m.destroy_entity(first);
+        m.resolve();
+        assert!(!m.entity(ent_handle).valid() && !m.element(ele_handle).valid());
+
+        // the freed slots are reused, but the stale handles stay stale
+        let second = m.create_entity("second".to_string());
+        let b = second.get_ref_mut().unwrap().add_element(B { bal: 4 }).unwrap();
+        let new_handle = second.get_ref().unwrap().get_handle();
+        assert!(new_handle.index() == ent_handle.index() && new_handle != ent_handle);
+        assert!(!m.entity(ent_handle).valid() && m.entity(new_handle) == second);
+        assert!(b.handle().unwrap() != ele_handle && !m.element(ele_handle).valid());
+
+        // the same slots in another Manager don't resolve
+        let mut other = Manager::new();
+        let third = other.create_entity("third".to_string());
+        third.get_ref_mut().unwrap().add_element(B { bal: 5 }).unwrap();
+        assert!(third.get_ref().unwrap().get_handle().index() == new_handle.index());
+        assert!(!other.entity(new_handle).valid() && !other.element(b.handle().unwrap()).valid());
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn test_parallel() {
+        use std::sync::atomic::{AtomicI32, Ordering};
+        use std::sync::Arc;
+        use crate::parallel::*;
+
+        let mut m = Manager::new();
+        for i in 0..100 {
+            let ent = m.create_entity(format!("ent {}", i));
+            ent.get_ref_mut().unwrap().add_element(A { val: i }).unwrap();
+            ent.get_ref_mut().unwrap().add_element(B { bal: i }).unwrap();
+        }
+
+        m.par_query_each_mut::<B>(|_, b| b.bal *= 2);
+        let sum = Arc::new(AtomicI32::new(0));
+        let total = sum.clone();
+        m.par_query_each::<B>(move |_, b| { total.fetch_add(b.bal, Ordering::Relaxed); });
+        assert!(sum.load(Ordering::Relaxed) == 2 * 4950);
+
+        // the first two run together, the last one waits for the first
+        let seen = Arc::new(AtomicI32::new(0));
+        let seen_by_system = seen.clone();
+        let systems = vec![
+            ParSystem::new("double_a", |view| view.write::<A>().into_iter().for_each(|mut a| a.get_ref_mut().unwrap().val *= 2)).writes::<A>(),
+            ParSystem::new("inc_b", |view| view.write::<B>().into_iter().for_each(|mut b| b.get_ref_mut().unwrap().bal += 1)).writes::<B>(),
+            ParSystem::new("sum_a", move |view| view.read::<A>().into_iter()
+                .for_each(|a| { seen_by_system.fetch_add(a.get_ref().unwrap().val, Ordering::Relaxed); })).reads::<A>()
+        ];
+        m.run_parallel(&systems);
+        assert!(seen.load(Ordering::Relaxed) == 2 * 4950);
+        assert!(m.query::<(A, B)>().into_iter().all(|(_, (a, b))| a.get_ref().unwrap().val + 1 == b.get_ref().unwrap().bal));
+
+        // the Manager itself can move to and be shared with other threads
+        fn assert_send_sync<T: Send + Sync>() { }
+        assert_send_sync::<Manager>();
+        let mut m = std::thread::spawn(move || {
+            m.update(1.0 / 60.0);
+            m
+        }).join().unwrap();
+        // structural changes from a parallel system go through the Manager's locks
+        let systems = vec![ParSystem::new("add_pos", |view| view.read::<A>().into_iter()
+            .for_each(|a| { a.get_owner().get_ref_mut().unwrap().add_element(PosRot { pos: [0.0; 3] }).unwrap(); })).reads::<A>()];
+        m.run_parallel(&systems);
+        assert!(m.of_type::<PosRot>().len() == 100 && m.added::<PosRot>().len() == 100);
+    }
+
+    #[test]
+    fn test_resources() {
+        use crate::borrow::*;
+
+        struct Gravity(i32);
+
+        #[derive(Clone, Serialize, Deserialize)]
+        struct Falling {
+            y: i32
+        }
+        impl Element for Falling {
+            fn update(&mut self, man: &mut Manager, _owner: EntAddr) {
+                self.y -= man.resource::<Gravity>().unwrap().0;
+            }
+        }
+
+        let mut m = Manager::new();
+        assert!(m.resource::<Gravity>().is_none() && !m.has_resource::<Gravity>());
+        m.insert_resource(Gravity(2));
+        let ent = m.create_entity("ent".to_string());
+        let falling = ent.get_ref_mut().unwrap().add_element(Falling { y: 10 }).unwrap();
+
+        m.update(1.0 / 60.0);
+        m.resource_mut::<Gravity>().unwrap().0 = 3;
+        m.update(1.0 / 60.0);
+        assert!(falling.get_ref().unwrap().y == 5);
+
+        {
+            let _held = m.resource::<Gravity>().unwrap();
+            assert!(m.resource::<Gravity>().is_some());
+            assert!(m.try_resource_mut::<Gravity>().err() == Some(BorrowError::AlreadyBorrowed(std::any::type_name::<Gravity>())));
+        }
+        // a caught panic doesn't leave the resource borrowed
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _held = m.resource_mut::<Gravity>().unwrap();
+            panic!("while holding Gravity");
+        }));
+        assert!(res.is_err() && m.try_resource_mut::<Gravity>().is_ok());
+        // a borrowed resource can't be removed or replaced, and stays usable
+        {
+            let held = m.resource::<Gravity>().unwrap();
+            assert!(matches!(m.try_remove_resource::<Gravity>(), Err(BorrowError::AlreadyBorrowed(_))));
+            let replaced = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| m.insert_resource(Gravity(7))));
+            assert!(replaced.is_err() && held.0 == 3);
+        }
+        assert!(m.remove_resource::<Gravity>().unwrap().0 == 3);
+        assert!(matches!(m.try_resource::<Gravity>(), Err(BorrowError::Dead(_))));
+    }
+
+    #[test]
+    fn test_time() {
+        use crate::time::*;
+
+        #[derive(Clone, Serialize, Deserialize)]
+        struct Body {
+            steps: u32,
+            frames: u32
+        }
+        impl Element for Body {
+            fn fixed_update(&mut self, _man: &mut Manager, _owner: EntAddr) {
+                self.steps += 1;
+            }
+            fn update(&mut self, man: &mut Manager, _owner: EntAddr) {
+                self.frames = man.resource::<Time>().unwrap().frame() as u32;
+            }
+        }
+
+        let mut m = Manager::new();
+        let ent = m.create_entity("ent".to_string());
+        let body = ent.get_ref_mut().unwrap().add_element(Body { steps: 0, frames: 0 }).unwrap();
+
+        // no fixed updates until a fixed timestep is set
+        m.update(0.5);
+        assert!(body.get_ref().unwrap().steps == 0 && body.get_ref().unwrap().frames == 1);
+        assert!(m.resource::<Time>().unwrap().delta() == 0.5 && m.resource::<Time>().unwrap().frame() == 1);
+
+        m.resource_mut::<Time>().unwrap().set_fixed_delta(Some(0.25));
+        m.update(0.6);
+        assert!(body.get_ref().unwrap().steps == 2);
+        assert!((m.resource::<Time>().unwrap().alpha() - 0.4).abs() < 1e-4);
+        m.update(0.2);
+        assert!(body.get_ref().unwrap().steps == 3 && body.get_ref().unwrap().frames == 3);
+
+        // a long frame is capped at max_fixed_steps
+        m.resource_mut::<Time>().unwrap().set_max_fixed_steps(4);
+        m.update(10.0);
+        assert!(body.get_ref().unwrap().steps == 7);
+        let time = m.resource::<Time>().unwrap();
+        assert!(time.frame() == 4 && (time.elapsed() - 11.3).abs() < 1e-4 && time.alpha() < 1.0);
+    }
+
+    #[test]
+    fn test_lifecycle_hooks() {
+        use std::cell::RefCell;
+
+        thread_local! {
+            static HOOKS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+        }
+        fn hook(name: &str) {
+            HOOKS.with(|hooks| hooks.borrow_mut().push(name.to_string()));
+        }
+
+        #[derive(Clone, Serialize, Deserialize)]
+        struct Tracker;
+        impl Element for Tracker {
+            fn on_added(&mut self, _man: &mut Manager, _owner: EntAddr) { hook("added"); }
+            fn on_removed(&mut self, _man: &mut Manager, _owner: EntAddr) { hook("removed"); }
+            fn on_owner_destroyed(&mut self, _man: &mut Manager, _owner: EntAddr) { hook("owner_destroyed"); }
+            fn on_parent_changed(&mut self, _man: &mut Manager, owner: EntAddr, old_parent: EntAddr) {
+                assert!(owner.get_ref().unwrap().get_parent() != old_parent);
+                hook("parent_changed");
+            }
+            fn on_deserialized(&mut self, _man: &mut Manager, _owner: EntAddr) { hook("deserialized"); }
+        }
+        let take = || HOOKS.with(|hooks| hooks.take());
+
+        let mut m = Manager::new();
+        let parent = m.create_entity("parent".to_string());
+        let ent = m.create_entity("ent".to_string());
+        let tracker = ent.get_ref_mut().unwrap().add_element(Tracker).unwrap();
+        assert!(take().is_empty());
+        m.resolve();
+        assert!(take() == ["added"]);
+
+        m.reparent(ent.clone(), parent.clone()).unwrap();
+        m.reparent(ent.clone(), parent.clone()).unwrap();
+        assert!(take() == ["parent_changed"]);
+
+        m.destroy_element(tracker.into());
+        m.resolve();
+        assert!(take() == ["removed"]);
+
+        ent.get_ref_mut().unwrap().add_element(Tracker).unwrap();
+        let mut scene = SceneSerde::new();
+        scene.register_element_creator(Tracker, "Tracker");
+        let all = m.all_entities();
+        let json = scene.serialize_scene(&mut m, all);
+        m.destroy_entity(parent);
+        m.resolve();
+        assert!(take() == ["added", "owner_destroyed"]);
+
+        scene.deserialize_scene(&mut m, json).unwrap();
+        assert!(take() == ["added", "deserialized"]);
+    }
+
+    #[test]
+    fn test_active_flags() {
+        let mut scene = SceneSerde::new();
+        scene.register_element_creator(A { val: 0 }, "A");
+        scene.register_element_creator(B { bal: 0 }, "B");
+
+        let mut m = Manager::new();
+        let parent = m.create_entity("parent".to_string());
+        let child = m.create_entity("child".to_string());
+        m.reparent(child.clone(), parent.clone()).unwrap();
+        let parent_a = parent.get_ref_mut().unwrap().add_element(A { val: 0 }).unwrap();
+        let child_a = child.get_ref_mut().unwrap().add_element(A { val: 0 }).unwrap();
+        let child_b = child.get_ref_mut().unwrap().add_element(B { bal: 0 }).unwrap();
+
+        // deactivating the parent deactivates its descendants
+        parent.get_ref_mut().unwrap().set_active(false);
+        assert!(!child.is_active() && child.get_ref().unwrap().is_active_self());
+        {
+            // a borrowed parent still counts with its own flag
+            let _held = parent.get_ref_mut().unwrap();
+            assert!(!child.is_active() && m.query::<A>().is_empty());
+        }
+        m.update(1.0 / 60.0);
+        assert!(parent_a.get_ref().unwrap().val == 0 && child_a.get_ref().unwrap().val == 0);
+        assert!(m.query::<A>().is_empty() && m.query_including_inactive::<A>().len() == 2);
+
+        parent.get_ref_mut().unwrap().set_active(true);
+        child_a.set_enabled(false);
+        m.update(1.0 / 60.0);
+        assert!(parent_a.get_ref().unwrap().val == 10 && child_a.get_ref().unwrap().val == 0);
+        assert!(m.query::<A>().len() == 1 && m.query::<(B, Without<A>)>().len() == 1);
+
+        // both flags survive a round trip through a scene and show up in patches
+        child.get_ref_mut().unwrap().set_active(false);
+        let all = m.all_entities();
+        let json = scene.serialize_scene(&mut m, all);
+        let mut loaded = Manager::new();
+        let res = scene.deserialize_scene(&mut loaded, json.clone()).unwrap();
+        let loaded_child = res.ents.iter().find(|ent| ent.get_ref().unwrap().name == "child").unwrap().clone();
+        assert!(!loaded_child.get_ref().unwrap().is_active_self());
+        assert!(!loaded_child.get_ref_mut().unwrap().query_element_addr::<A>().is_enabled());
+        assert!(loaded_child.get_ref_mut().unwrap().query_element_addr::<B>().is_enabled());
+
+        child.get_ref_mut().unwrap().set_active(true);
+        child_a.set_enabled(true);
+        child_b.set_enabled(false);
+        let all = m.all_entities();
+        let patch = SceneSerde::diff(&json, &scene.serialize_scene(&mut m, all)).unwrap();
+        scene.apply_patch(&mut loaded, &patch).unwrap();
+        assert!(loaded_child.is_active());
+        assert!(loaded_child.get_ref_mut().unwrap().query_element_addr::<A>().is_enabled());
+        assert!(!loaded_child.get_ref_mut().unwrap().query_element_addr::<B>().is_enabled());
+        scene.apply_patch(&mut loaded, &patch.inverse()).unwrap();
+        let all = loaded.all_entities();
+        assert!(SceneSerde::diff(&json, &scene.serialize_scene(&mut loaded, all)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tags_and_layers() {
+        let mut scene = SceneSerde::new();
+        scene.register_element_creator(A { val: 0 }, "A");
+
+        let mut m = Manager::new();
+        let player = m.create_entity("player".to_string());
+        let goblin = m.create_entity("enemy_goblin".to_string());
+        let orc = m.create_entity("enemy_orc".to_string());
+        for ent in [&player, &goblin, &orc] {
+            ent.get_ref_mut().unwrap().add_element(A { val: 0 }).unwrap();
+        }
+        assert!(goblin.get_ref_mut().unwrap().add_tag("enemy") && !goblin.get_ref_mut().unwrap().add_tag("enemy"));
+        orc.get_ref_mut().unwrap().add_tag("enemy");
+        orc.get_ref_mut().unwrap().add_tag("dead");
+        player.get_ref_mut().unwrap().set_layers(0b110);
+
+        assert!(m.find_by_tag("enemy") == vec![goblin.clone(), orc.clone()]);
+        assert!(m.find_by_name("enemy_orc") == vec![orc.clone()] && m.find_by_name("enemy").is_empty());
+        assert!(m.find_by_name_prefix("enemy_") == vec![goblin.clone(), orc.clone()]);
+        assert!(m.find_in_layers(0b100) == vec![player.clone()] && m.find_in_layers(DEFAULT_LAYERS).len() == 2);
+
+        let alive = m.query_with::<A>(&QueryFilter::new().tag("enemy").without_tag("dead"));
+        assert!(alive.len() == 1 && alive[0].0 == goblin);
+        assert!(m.query_with::<A>(&QueryFilter::new().layers(0b10)).len() == 1);
+        // entities in no layer at all still match unfiltered queries
+        goblin.get_ref_mut().unwrap().set_layers(0);
+        assert!(m.query::<A>().len() == 3 && m.query_with::<A>(&QueryFilter::new().layers(u32::MAX)).len() == 2);
+        goblin.get_ref_mut().unwrap().set_layers(DEFAULT_LAYERS);
+
+        // tags and layers are saved with the scene and picked up by patches
+        let all = m.all_entities();
+        let json = scene.serialize_scene(&mut m, all);
+        let mut loaded = Manager::new();
+        scene.deserialize_scene(&mut loaded, json.clone()).unwrap();
+        let loaded_orc = loaded.find_by_name("enemy_orc")[0].clone();
+        assert!(loaded_orc.get_ref().unwrap().get_tags() == ["enemy", "dead"]);
+        assert!(loaded.find_in_layers(0b100).len() == 1);
+
+        assert!(orc.get_ref_mut().unwrap().remove_tag("dead") && !orc.get_ref_mut().unwrap().remove_tag("dead"));
+        player.get_ref_mut().unwrap().set_layers(DEFAULT_LAYERS);
+        let all = m.all_entities();
+        let patch = SceneSerde::diff(&json, &scene.serialize_scene(&mut m, all)).unwrap();
+        scene.apply_patch(&mut loaded, &patch).unwrap();
+        assert!(loaded_orc.get_ref().unwrap().get_tags() == ["enemy"] && loaded.find_in_layers(0b100).is_empty());
+        scene.apply_patch(&mut loaded, &patch.inverse()).unwrap();
+        let all = loaded.all_entities();
+        assert!(SceneSerde::diff(&json, &scene.serialize_scene(&mut loaded, all)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_entity_paths() {
+        let mut m = Manager::new();
+        let level = m.create_entity("Level".to_string());
+        let door = m.create_entity("Door".to_string());
+        let hinge = m.create_entity("Hinge".to_string());
+        let window = m.create_entity("Window".to_string());
+        // a second door without a hinge, found first in child order
+        let other_door = m.create_entity("Door".to_string());
+        m.reparent(other_door.clone(), level.clone()).unwrap();
+        m.reparent(door.clone(), level.clone()).unwrap();
+        m.reparent(hinge.clone(), door.clone()).unwrap();
+        m.reparent(window.clone(), level.clone()).unwrap();
+
+        assert!(hinge.path().unwrap() == "Level/Door/Hinge" && level.path().unwrap() == "Level");
+        assert!(m.find_by_path("Level/Door/Hinge") == hinge);
+        assert!(m.find_by_path("Level/Door") == other_door);
+        assert!(!m.find_by_path("Level/Hinge").valid() && !m.find_by_path("Door").valid());
+
+        assert!(m.find_relative(&hinge, "../../Window") == window);
+        assert!(m.find_relative(&window, "./../Door/Hinge") == hinge);
+        assert!(m.find_relative(&hinge, "/Level") == level);
+        assert!(m.find_relative(&level, "..") == EntAddr::new() && !m.find_relative(&level, "../..").valid());
+
+        // paths follow renames and reparenting
+        door.get_ref_mut().unwrap().name = "Gate".to_string();
+        m.reparent(hinge.clone(), window.clone()).unwrap();
+        assert!(hinge.path().unwrap() == "Level/Window/Hinge" && m.find_by_path("Level/Gate").valid());
+        {
+            let _held = window.get_ref_mut().unwrap();
+            assert!(hinge.path().is_none());
+        }
+        let dead = hinge.clone();
+        m.destroy_entity(hinge);
+        m.resolve();
+        assert!(dead.path().is_none() && !m.find_by_path("Level/Window/Hinge").valid());
+    }
+
+    #[test]
+    fn test_query_multi_instance() {
+        let mut m = Manager::new();
+        let ent = m.create_entity("ent".to_string());
+        let other = m.create_entity("other".to_string());
+        for radius in [1.0, 2.0] {
+            ent.get_ref_mut().unwrap().add_element(Collider { radius, target: EleAddr::new() }).unwrap();
+        }
+        other.get_ref_mut().unwrap().add_element(Collider { radius: 3.0, target: EleAddr::new() }).unwrap();
+
+        // an entity matches once, with the first instance
+        let matches = m.query::<Collider>();
+        assert!(matches.len() == 2 && matches[0].0 == ent && matches[1].0 == other);
+        let mut radii = Vec::new();
+        m.query_each::<Collider, _>(|_, c| radii.push(c.radius));
+        assert!(radii == vec![1.0, 3.0]);
+    }
+}
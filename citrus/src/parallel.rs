@@ -0,0 +1,124 @@
+use std::{any::TypeId, collections::HashMap};
+
+use rayon::prelude::*;
+
+use crate::element::*;
+use crate::entity::*;
+
+// A system that only touches elements of the types it declares, run by Manager::run_parallel
+// To run them as part of the schedule, register a regular system calling run_parallel:
+//   man.add_system(Stage::Update, "simulation", move |man: &mut Manager| man.run_parallel(&systems));
+pub struct ParSystem {
+    name: String,
+    reads: Vec<TypeId>,
+    writes: Vec<TypeId>,
+    run: Box<dyn Fn(&ElementView) + Send + Sync>
+}
+
+impl ParSystem {
+    pub fn new(name: &str, run: impl Fn(&ElementView) + Send + Sync + 'static) -> Self {
+        Self {
+            name: name.to_string(),
+            reads: Vec::new(),
+            writes: Vec::new(),
+            run: Box::new(run)
+        }
+    }
+    pub fn reads<T: Element>(mut self) -> Self {
+        self.reads.push(TypeId::of::<T>());
+        self
+    }
+    pub fn writes<T: Element>(mut self) -> Self {
+        self.writes.push(TypeId::of::<T>());
+        self
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn touches(&self, id: &TypeId) -> bool {
+        self.reads.contains(id) || self.writes.contains(id)
+    }
+    fn conflicts(&self, other: &ParSystem) -> bool {
+        self.writes.iter().any(|id| other.touches(id)) || other.writes.iter().any(|id| self.touches(id))
+    }
+}
+
+// The elements a ParSystem declared, gathered before the systems run
+// Borrows still go through the element borrow counts, so overlapping access fails instead of racing
+pub struct ElementView<'a> {
+    elements: &'a HashMap<TypeId, Vec<EleAddrErased>>,
+    system: &'a ParSystem
+}
+
+impl<'a> ElementView<'a> {
+    // Panics if the system declared neither reads::<T>() nor writes::<T>()
+    pub fn read<T: Element>(&self) -> Vec<EleAddr<T>> {
+        assert!(self.system.touches(&TypeId::of::<T>()), "System \"{}\" didn't declare reading \"{}\"", self.system.name, std::any::type_name::<T>());
+        self.of_type::<T>()
+    }
+    // Panics if the system didn't declare writes::<T>()
+    pub fn write<T: Element>(&self) -> Vec<EleAddr<T>> {
+        assert!(self.system.writes.contains(&TypeId::of::<T>()), "System \"{}\" didn't declare writing \"{}\"", self.system.name, std::any::type_name::<T>());
+        self.of_type::<T>()
+    }
+
+    fn of_type<T: Element>(&self) -> Vec<EleAddr<T>> {
+        self.elements.get(&TypeId::of::<T>())
+        .map_or(Vec::new(), |addrs| addrs.iter().map(|ele| ele.downcast::<T>()).collect())
+    }
+}
+
+impl Manager {
+    // Runs systems in the order given, except that consecutive systems that don't conflict
+    // (neither writes a type the other reads or writes) run at the same time on rayon's thread pool
+    // Systems can add elements through their owners, which locks the Manager's element index; the elements'
+    // on_added hooks run when the Manager resolves once every system has run
+    pub fn run_parallel(&mut self, systems: &[ParSystem]) {
+        let elements = systems.iter()
+        .flat_map(|sys| sys.reads.iter().chain(sys.writes.iter()))
+        .map(|id| (*id, self.of_type_id(id)))
+        .collect::<HashMap<TypeId, Vec<EleAddrErased>>>();
+
+        let mut batches: Vec<Vec<&ParSystem>> = Vec::new();
+        for sys in systems.iter() {
+            match batches.last_mut() {
+                Some(batch) if !batch.iter().any(|other| other.conflicts(sys)) => batch.push(sys),
+                _ => batches.push(vec![sys])
+            }
+        }
+        for batch in batches.into_iter() {
+            rayon::scope(|scope| {
+                for sys in batch.into_iter() {
+                    let elements = &elements;
+                    scope.spawn(move |_| (sys.run)(&ElementView { elements, system: sys }));
+                }
+            });
+        }
+
+        self.resolve();
+    }
+    // Calls f on every element of type T, spread over rayon's thread pool
+    // Elements that are borrowed elsewhere at the time are skipped, like disabled ones and ones on inactive entities
+    pub fn par_query_each<T: Element>(&mut self, f: impl Fn(EntAddr, &T) + Send + Sync) {
+        self.active_of_type::<T>().into_par_iter().for_each(|ele| {
+            if let Ok(ele_ref) = ele.try_get_ref() {
+                f(ele.get_owner(), &ele_ref);
+            }
+        });
+    }
+    pub fn par_query_each_mut<T: Element>(&mut self, f: impl Fn(EntAddr, &mut T) + Send + Sync) {
+        self.active_of_type::<T>().into_par_iter().for_each(|mut ele| {
+            let owner = ele.get_owner();
+            if let Ok(mut ele_ref) = ele.try_get_ref_mut() {
+                f(owner, &mut ele_ref);
+            }
+        });
+    }
+
+    fn active_of_type<T: Element>(&mut self) -> Vec<EleAddr<T>> {
+        self.of_type::<T>().into_iter()
+        .filter(|ele| ele.is_enabled() && ele.get_owner().is_active())
+        .collect()
+    }
+}
@@ -0,0 +1,159 @@
+use std::{any::TypeId, marker::PhantomData};
+
+use crate::element::*;
+use crate::entity::*;
+
+// Filter that requires an element of type T without fetching it
+pub struct With<T: Element>(PhantomData<T>);
+
+// Filter that rejects entities holding an element of type T
+pub struct Without<T: Element>(PhantomData<T>);
+
+// Anything that can appear in a Manager::query, e.g. (A, Option<B>, Without<C>)
+// fetch returns None when the entity doesn't match; disabled elements count as absent unless include_disabled
+pub trait QueryParam {
+    type Addr: Clone;
+    type Ref<'a>;
+
+    // Element types an entity must hold to match, used to narrow the search through the Manager's type index
+    fn required_types(_ids: &mut Vec<TypeId>) { }
+    fn fetch(ent: &mut Entity, include_disabled: bool) -> Option<Self::Addr>;
+    fn borrow<'a>(addr: &mut Self::Addr) -> Option<Self::Ref<'a>>;
+}
+
+impl<T: Element> QueryParam for T {
+    type Addr = EleAddr<T>;
+    type Ref<'a> = EleRefMut<'a, T>;
+
+    fn required_types(ids: &mut Vec<TypeId>) {
+        ids.push(TypeId::of::<T>());
+    }
+    fn fetch(ent: &mut Entity, include_disabled: bool) -> Option<Self::Addr> {
+        ent.query_elements::<T>()
+        .into_iter()
+        .find(|addr| include_disabled || addr.is_enabled())
+    }
+    // None when the element is already borrowed, e.g. by its own update
+    fn borrow<'a>(addr: &mut Self::Addr) -> Option<Self::Ref<'a>> {
+        addr.try_get_ref_mut().ok()
+    }
+}
+
+impl<T: Element> QueryParam for Option<T> {
+    type Addr = Option<EleAddr<T>>;
+    type Ref<'a> = Option<EleRefMut<'a, T>>;
+
+    fn fetch(ent: &mut Entity, include_disabled: bool) -> Option<Self::Addr> {
+        Some(T::fetch(ent, include_disabled))
+    }
+    fn borrow<'a>(addr: &mut Self::Addr) -> Option<Self::Ref<'a>> {
+        match addr {
+            Some(addr) => addr.try_get_ref_mut().ok().map(Some),
+            None => Some(None)
+        }
+    }
+}
+
+impl<T: Element> QueryParam for With<T> {
+    type Addr = ();
+    type Ref<'a> = ();
+
+    fn required_types(ids: &mut Vec<TypeId>) {
+        T::required_types(ids);
+    }
+    fn fetch(ent: &mut Entity, include_disabled: bool) -> Option<Self::Addr> {
+        T::fetch(ent, include_disabled).map(|_| ())
+    }
+    fn borrow<'a>(_addr: &mut Self::Addr) -> Option<Self::Ref<'a>> {
+        Some(())
+    }
+}
+
+impl<T: Element> QueryParam for Without<T> {
+    type Addr = ();
+    type Ref<'a> = ();
+
+    fn fetch(ent: &mut Entity, include_disabled: bool) -> Option<Self::Addr> {
+        match T::fetch(ent, include_disabled) {
+            Some(_) => None,
+            None => Some(())
+        }
+    }
+    fn borrow<'a>(_addr: &mut Self::Addr) -> Option<Self::Ref<'a>> {
+        Some(())
+    }
+}
+
+macro_rules! impl_query_param_tuple {
+    ($($name: ident),*) => {
+        #[allow(non_snake_case)]
+        impl<$($name: QueryParam),*> QueryParam for ($($name,)*) {
+            type Addr = ($($name::Addr,)*);
+            type Ref<'a> = ($($name::Ref<'a>,)*);
+
+            fn required_types(ids: &mut Vec<TypeId>) {
+                $($name::required_types(ids);)*
+            }
+            fn fetch(ent: &mut Entity, include_disabled: bool) -> Option<Self::Addr> {
+                Some(($($name::fetch(ent, include_disabled)?,)*))
+            }
+            fn borrow<'a>(addr: &mut Self::Addr) -> Option<Self::Ref<'a>> {
+                let ($($name,)*) = addr;
+                Some(($($name::borrow($name)?,)*))
+            }
+        }
+    };
+}
+
+impl_query_param_tuple!(A);
+impl_query_param_tuple!(A, B);
+impl_query_param_tuple!(A, B, C);
+impl_query_param_tuple!(A, B, C, D);
+impl_query_param_tuple!(A, B, C, D, E);
+impl_query_param_tuple!(A, B, C, D, E, F);
+impl_query_param_tuple!(A, B, C, D, E, F, G);
+impl_query_param_tuple!(A, B, C, D, E, F, G, H);
+
+// Conditions on the entity itself for Manager::query_with, e.g. QueryFilter::new().tag("enemy").without_tag("dead")
+// Like Manager::query, a new filter leaves out inactive entities and disabled elements
+#[derive(Clone, Debug, Default)]
+pub struct QueryFilter {
+    include_inactive: bool,
+    tags: Vec<String>,
+    without_tags: Vec<String>,
+    // None accepts any layers, including none at all
+    layers: Option<u32>
+}
+
+impl QueryFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn include_inactive(mut self) -> Self {
+        self.include_inactive = true;
+        self
+    }
+    // Requires the tag, on top of any other required tags
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+    pub fn without_tag(mut self, tag: &str) -> Self {
+        self.without_tags.push(tag.to_string());
+        self
+    }
+    // Requires the entity to be in any of the layers in mask
+    pub fn layers(mut self, mask: u32) -> Self {
+        self.layers = Some(mask);
+        self
+    }
+
+    pub(crate) fn includes_inactive(&self) -> bool {
+        self.include_inactive
+    }
+    pub(crate) fn matches(&self, ent: &Entity) -> bool {
+        self.layers.is_none_or(|mask| ent.in_layers(mask))
+        && self.tags.iter().all(|tag| ent.has_tag(tag))
+        && !self.without_tags.iter().any(|tag| ent.has_tag(tag))
+    }
+}
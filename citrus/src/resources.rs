@@ -0,0 +1,172 @@
+use std::{any::TypeId, cell::UnsafeCell, collections::HashMap, ops::{Deref, DerefMut}};
+
+use crate::borrow::*;
+use crate::sync::*;
+
+// Typed singletons stored on a Manager, e.g. time, input state or asset caches
+// References are borrow checked at runtime like element references, so they can be held while the Manager is in use
+#[derive(Default)]
+pub struct Resources {
+    map: HashMap<TypeId, ResourceHolder>
+}
+
+struct ResourceHolder {
+    data: Box<UnsafeCell<AnyShared>>,
+    internal: Shared<BorrowCount>,
+    type_name: &'static str
+}
+
+// The data is Send + Sync with the sync feature and only reached through the atomic borrow count
+#[cfg(feature = "sync")]
+unsafe impl Sync for ResourceHolder {}
+
+impl Drop for ResourceHolder {
+    fn drop(&mut self) {
+        if std::thread::panicking() { return; }
+        assert!(self.internal.get() == 0, "Resource \"{}\" dropped while references to it are held", self.type_name);
+    }
+}
+
+impl Resources {
+    pub fn new() -> Self {
+        Self {
+            map: HashMap::new()
+        }
+    }
+
+    // Replaces the resource of the same type, if any; panics if that one is borrowed, leaving it in place
+    #[track_caller]
+    pub fn insert<R: MaybeSync + 'static>(&mut self, res: R) {
+        if let Err(err) = self.check_unborrowed::<R>() {
+            self.borrow_panic::<R>(err);
+        }
+        self.map.insert(TypeId::of::<R>(), ResourceHolder {
+            data: Box::new(UnsafeCell::new(res)),
+            internal: Shared::new(BorrowCount::new()),
+            type_name: std::any::type_name::<R>()
+        });
+    }
+    // Panics if the resource is borrowed, leaving it in place
+    #[track_caller]
+    pub fn remove<R: 'static>(&mut self) -> Option<R> {
+        let res = self.try_remove();
+        self.missing_to_none::<R, _>(res)
+    }
+    // Fails without removing the resource if it's borrowed
+    pub fn try_remove<R: 'static>(&mut self) -> Result<R, BorrowError> {
+        self.check_unborrowed::<R>()?;
+        let mut holder = self.map.remove(&TypeId::of::<R>()).ok_or(BorrowError::Dead(std::any::type_name::<R>()))?;
+        let data = std::mem::replace(&mut holder.data, Box::new(UnsafeCell::new(())));
+        // the map only holds an R under TypeId::of::<R>()
+        let data = unsafe { Box::from_raw(Box::into_raw(data) as *mut UnsafeCell<R>) };
+        Ok(data.into_inner())
+    }
+    pub fn contains<R: 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<R>())
+    }
+
+    #[track_caller]
+    pub fn try_get<'a, R: 'static>(&self) -> Result<ResRef<'a, R>, BorrowError> {
+        let holder = self.map.get(&TypeId::of::<R>()).ok_or(BorrowError::Dead(std::any::type_name::<R>()))?;
+        holder.internal.acquire(false, holder.type_name)?;
+        let data = unsafe { &*holder.data.get() }.downcast_ref::<R>().unwrap() as *const R;
+
+        Ok(ResRef {
+            data: unsafe { &*data }, // rewrite the lifetime
+            internal: Shared::downgrade(&holder.internal),
+            _token: BorrowToken::new(borrow_key(&Shared::downgrade(&holder.internal)), holder.type_name, false)
+        })
+    }
+    #[track_caller]
+    pub fn try_get_mut<'a, R: 'static>(&self) -> Result<ResRefMut<'a, R>, BorrowError> {
+        let holder = self.map.get(&TypeId::of::<R>()).ok_or(BorrowError::Dead(std::any::type_name::<R>()))?;
+        holder.internal.acquire(true, holder.type_name)?;
+        // the borrow count guarantees this is the only reference
+        let data = unsafe { &mut *holder.data.get() }.downcast_mut::<R>().unwrap() as *mut R;
+
+        Ok(ResRefMut {
+            data: unsafe { &mut *data }, // rewrite the lifetime
+            internal: Shared::downgrade(&holder.internal),
+            _token: BorrowToken::new(borrow_key(&Shared::downgrade(&holder.internal)), holder.type_name, true)
+        })
+    }
+    // None if there is no such resource, panics if it's already borrowed
+    #[track_caller]
+    pub fn get<'a, R: 'static>(&self) -> Option<ResRef<'a, R>> {
+        self.missing_to_none::<R, _>(self.try_get())
+    }
+    #[track_caller]
+    pub fn get_mut<'a, R: 'static>(&self) -> Option<ResRefMut<'a, R>> {
+        self.missing_to_none::<R, _>(self.try_get_mut())
+    }
+
+    // Fails if there is a resource of type R and it's borrowed
+    fn check_unborrowed<R: 'static>(&self) -> Result<(), BorrowError> {
+        if let Some(holder) = self.map.get(&TypeId::of::<R>()) {
+            holder.internal.acquire(true, holder.type_name)?;
+            holder.internal.release(true);
+        }
+        Ok(())
+    }
+    #[track_caller]
+    fn missing_to_none<R: 'static, T>(&self, res: Result<T, BorrowError>) -> Option<T> {
+        match res {
+            Ok(r) => Some(r),
+            Err(BorrowError::Dead(_)) => None,
+            Err(err) => self.borrow_panic::<R>(err)
+        }
+    }
+    #[track_caller]
+    fn borrow_panic<R: 'static>(&self, err: BorrowError) -> ! {
+        let holder = &self.map[&TypeId::of::<R>()];
+        panic!("{}{}", err, describe_holders(borrow_key(&Shared::downgrade(&holder.internal))))
+    }
+}
+
+pub struct ResRef<'a, R> {
+    data: &'a R,
+    internal: WeakShared<BorrowCount>,
+    _token: BorrowToken
+}
+pub struct ResRefMut<'a, R> {
+    data: &'a mut R,
+    internal: WeakShared<BorrowCount>,
+    _token: BorrowToken
+}
+
+impl<'a, R> Drop for ResRef<'a, R> {
+    fn drop(&mut self) {
+        // Released even while unwinding, so a caught panic doesn't leave the resource borrowed
+        if let Some(rc) = self.internal.upgrade() {
+            let count = rc.release(false);
+            assert!(std::thread::panicking() || count >= 0, "Resource \"{}\"'s ref count somehow dropped below zero", std::any::type_name::<R>());
+        }
+    }
+}
+impl<'a, R> Drop for ResRefMut<'a, R> {
+    fn drop(&mut self) {
+        if let Some(rc) = self.internal.upgrade() {
+            let count = rc.release(true);
+            assert!(std::thread::panicking() || count == 0, "Resource \"{}\"'s ref count didn't equal zero when dropping mutable reference", std::any::type_name::<R>());
+        }
+    }
+}
+impl<'a, R> Deref for ResRef<'a, R> {
+    type Target = R;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+impl<'a, R> Deref for ResRefMut<'a, R> {
+    type Target = R;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+impl<'a, R> DerefMut for ResRefMut<'a, R> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.data
+    }
+}
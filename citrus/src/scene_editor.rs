@@ -1,10 +1,13 @@
-use std::{any::TypeId, cell::RefCell, fs, rc::Rc};
+use std::{cell::RefCell, fs, rc::Rc};
 use uuid::Uuid;
 use imgui::*;
 use std::collections::HashSet;
 
 use crate::editor_helpers;
+use crate::element::*;
 use crate::entity::*;
+use crate::scene_history::*;
+use crate::scene_patch::*;
 use crate::scene_serde::*;
 
 fn uuid_truncated(id: Uuid) -> String {
@@ -14,9 +17,11 @@ fn uuid_truncated(id: Uuid) -> String {
 #[derive(Clone)]
 struct SelectedEnt {
     addr: EntAddr,
-    selected_element: Option<TypeId>,
+    selected_element: Option<EleAddrErased>,
     selected_element_label: String,
-    creator_search: String
+    creator_search: String,
+    // The comma separated tags being typed, applied when editing ends
+    tags_text: Option<String>
 }
 
 impl SelectedEnt {
@@ -25,7 +30,8 @@ impl SelectedEnt {
             addr,
             selected_element: None,
             selected_element_label: String::new(),
-            creator_search: "".to_string()
+            creator_search: "".to_string(),
+            tags_text: None
         }
     }
 }
@@ -33,13 +39,25 @@ impl SelectedEnt {
 pub struct SceneEditor {
     selected_list: Vec<Rc<RefCell<SelectedEnt>>>,
     ents_expanded: HashSet<EntAddr>,
+    history: SceneHistory
 }
 
 impl SceneEditor {
     pub fn new() -> Self {
         Self {
             selected_list: Vec::new(),
-            ents_expanded: HashSet::new()
+            ents_expanded: HashSet::new(),
+            history: SceneHistory::new()
+        }
+    }
+    pub fn undo(&mut self, scene: &mut SceneSerde, man: &mut Manager) {
+        if let Err(err) = self.history.undo(scene, man) {
+            println!("Undo failed:\n{}", err);
+        }
+    }
+    pub fn redo(&mut self, scene: &mut SceneSerde, man: &mut Manager) {
+        if let Err(err) = self.history.redo(scene, man) {
+            println!("Redo failed:\n{}", err);
         }
     }
     fn save_scene(&mut self, scene: &mut SceneSerde, man: &mut Manager, name: &str) -> Result<(), std::io::Error> {
@@ -75,6 +93,7 @@ impl SceneEditor {
         }
 
         let truncated_id = format!("{}", ent_addr.get_ref_mut().unwrap().get_id().to_string());
+        let history = &mut self.history;
 
         let mut opened: bool = true;
         Window::new(&*ImString::new(truncated_id.as_str()))
@@ -83,15 +102,67 @@ impl SceneEditor {
         .size([400.0, 400.0], Condition::FirstUseEver)
         .opened(&mut opened)
         .build(ui, move || {
+            ui.text(format!("Path: {}", ent_addr.path().unwrap_or_default()));
+            {
+                let before = ent_addr.get_ref().unwrap().name.clone();
+                if ui.input_text(":Name", &mut ent_addr.get_ref_mut().unwrap().name).build() {
+                    let after = ent_addr.get_ref().unwrap().name.clone();
+                    let patch = ScenePatch::entity_modified(&ent_addr, Some((before, after)), None, vec![]);
+                    history.push("Rename entity", patch, Some(format!("Name {}", truncated_id)));
+                }
+            }
+
+            {
+                let before = ent_addr.get_ref().unwrap().is_active_self();
+                let mut active = before;
+                if ui.checkbox(":Active", &mut active) {
+                    ent_addr.get_ref_mut().unwrap().set_active(active);
+                    history.push("Toggle entity", ScenePatch::entity_activated(&ent_addr, before, active), None);
+                }
+            }
+
+            {
+                // Comma separated, applied on enter or when the field loses focus
+                let before = ent_addr.get_ref().unwrap().get_tags().to_vec();
+                let mut text = (*selected).borrow().tags_text.clone().unwrap_or_else(|| before.join(", "));
+                let entered = ui.input_text(":Tags", &mut text).enter_returns_true(true).build();
+                if entered || ui.is_item_deactivated_after_edit() {
+                    let after = text.split(',').map(|tag| tag.trim()).filter(|tag| !tag.is_empty()).map(|tag| tag.to_string()).collect::<Vec<String>>();
+                    ent_addr.get_ref_mut().unwrap().set_tags(after);
+                    let after = ent_addr.get_ref().unwrap().get_tags().to_vec();
+                    history.push("Retag entity", ScenePatch::entity_tagged(&ent_addr, before, after), None);
+                    (*selected).borrow_mut().tags_text = None;
+                } else {
+                    (*selected).borrow_mut().tags_text = match ui.is_item_active() {
+                        true => Some(text),
+                        false => None
+                    };
+                }
+            }
+
             {
-                ui.input_text(":Name", &mut ent_addr.get_ref_mut().unwrap().name).build();
+                let before = ent_addr.get_ref().unwrap().get_layers();
+                let mut layers = before;
+                ui.text("Layers");
+                for layer in 0..32 {
+                    if layer % 8 != 0 {
+                        ui.same_line();
+                    }
+                    ui.checkbox_flags(format!("##Layer {}", layer), &mut layers, 1 << layer);
+                }
+                if layers != before {
+                    ent_addr.get_ref_mut().unwrap().set_layers(layers);
+                    history.push("Change entity layers", ScenePatch::entity_layers_changed(&ent_addr, before, layers), None);
+                }
             }
 
             {
                 let mut parent = ent_addr.get_ref().unwrap().get_parent();
+                let before = parent.clone();
                 if editor_helpers::select_entity(&mut parent, "Parent", ui, man) {
-                    if let Err(_err) = man.reparent(ent_addr.clone(), parent.clone()) {
-                        println!("Reparenting would have created a cycle")
+                    match man.reparent(ent_addr.clone(), parent.clone()) {
+                        Ok(()) => history.push("Reparent entity", ScenePatch::entity_modified(&ent_addr, None, Some((before, parent)), vec![]), None),
+                        Err(_err) => println!("Reparenting would have created a cycle")
                     };
                 }
             }
@@ -100,57 +171,96 @@ impl SceneEditor {
             ui.input_text(":Search Elements", &mut (*selected).borrow_mut().creator_search).build();
             let list = scene.find_creators((*selected).borrow().creator_search.as_str());
             for entry in list.iter() {
-                let mut select_pos: Option<[f32; 2]> = None;
-                let style = ui.push_style_color(StyleColor::ButtonActive, [1_f32, 1_f32, 1_f32, 1_f32]);
-                if ent_addr.get_ref_mut().unwrap().query_element_addr_by_id(&entry.id).valid() {
-                    let (style0, style1) = (
+                let instances = ent_addr.get_ref_mut().unwrap().query_elements_by_id(&entry.id);
+                let can_create = entry.multi_instance || instances.is_empty();
+
+                // Each instance of a multi_instance type gets its own row
+                for (instance, ele_addr) in instances.into_iter().enumerate() {
+                    let label = match entry.multi_instance {
+                        true => format!("{} #{}", entry.name, instance),
+                        false => entry.name.clone()
+                    };
+                    let cursor = ui.cursor_pos();
+                    let (style, style0, style1) = (
+                        ui.push_style_color(StyleColor::ButtonActive, [1_f32, 1_f32, 1_f32, 1_f32]),
                         ui.push_style_color(StyleColor::Button, [0.5_f32, 0_f32, 0_f32, 1_f32]),
                         ui.push_style_color(StyleColor::ButtonHovered, [1_f32, 0.5_f32, 0.5_f32, 1_f32])
                     );
-                    select_pos = Some(ui.cursor_pos());
-                    if ui.button_with_size(&*ImString::new(("Destroy ".to_owned() + entry.name.as_str()).as_str()), [200_f32, 20_f32]) {
-                        let ele_addr = ent_addr.get_ref_mut().unwrap().query_element_addr_by_id(&entry.id);
-                        man.destroy_element(ele_addr);
+                    if ui.button_with_size(&*ImString::new(("Destroy ".to_owned() + label.as_str()).as_str()), [200_f32, 20_f32]) {
+                        let payload = ele_addr.get_ref().unwrap().ecs_serialize();
+                        let enabled = ele_addr.is_enabled();
+                        man.destroy_element(ele_addr.clone());
+                        let change = ElementChange::Removed { name: entry.name.clone(), instance, payload, enabled };
+                        history.push(&format!("Destroy {}", label), ScenePatch::entity_modified(&ent_addr, None, None, vec![change]), None);
 
                         man.resolve();
 
-                        if (*selected).borrow().selected_element == Some(entry.id) {
+                        if (*selected).borrow().selected_element.as_ref() == Some(&ele_addr) {
                             (*selected).borrow_mut().selected_element = None;
                             (*selected).borrow_mut().selected_element_label = "(None)".to_string();
                         }
                     }
                     style1.pop();
                     style0.pop();
-                } else {
-                    if ui.button_with_size(&*ImString::new(("Create  ".to_owned() + entry.name.as_str()).as_str()), [200_f32, 20_f32]) {
-                        assert!((*entry.creator)(ent_addr.clone()).valid());
-                    }
-                }
-                style.pop();
-                
-                if let Some(cursor) = select_pos {
+                    style.pop();
+
                     ui.set_cursor_pos([cursor[0] + 220_f32, cursor[1]]);
-                    let style = match (*selected).borrow().selected_element == Some(entry.id) {
+                    let style = match (*selected).borrow().selected_element.as_ref() == Some(&ele_addr) {
                         true => Some(ui.push_style_color(StyleColor::Button, [0_f32, 0.5_f32, 0_f32, 1_f32])),
                         false => None
                     };
-                    if ui.button_with_size(format!("Select {}", entry.name), [150_f32, 20_f32]) {
-                        (*selected).borrow_mut().selected_element = Some(entry.id);
+                    if ui.button_with_size(format!("Select {}", label), [150_f32, 20_f32]) {
+                        (*selected).borrow_mut().selected_element = Some(ele_addr.clone());
                         (*selected).borrow_mut().selected_element_label = entry.name.clone();
                     }
                     if let Some(st) = style {
                         st.pop();
                     }
+
+                    ui.same_line();
+                    let mut enabled = ele_addr.is_enabled();
+                    if ui.checkbox(format!("##Enabled {}", label), &mut enabled) {
+                        ele_addr.set_enabled(enabled);
+                        let change = ElementChange::Enabled { name: entry.name.clone(), instance, enabled: (!enabled, enabled) };
+                        history.push(&format!("Toggle {}", label), ScenePatch::entity_modified(&ent_addr, None, None, vec![change]), None);
+                    }
+                }
+
+                if can_create {
+                    let style = ui.push_style_color(StyleColor::ButtonActive, [1_f32, 1_f32, 1_f32, 1_f32]);
+                    if ui.button_with_size(&*ImString::new(("Create  ".to_owned() + entry.name.as_str()).as_str()), [200_f32, 20_f32]) {
+                        let ele_addr = (*entry.creator)(ent_addr.clone());
+                        assert!(ele_addr.valid());
+                        let instance = ele_addr.instance_index().unwrap();
+                        let change = ElementChange::Added { name: entry.name.clone(), instance, payload: ele_addr.get_ref().unwrap().ecs_serialize(), enabled: true };
+                        history.push(&format!("Create {}", entry.name), ScenePatch::entity_modified(&ent_addr, None, None, vec![change]), None);
+                    }
+                    style.pop();
                 }
             }
 
-            if let Some(selected_id) = (*selected).borrow().selected_element {
-                ui.text(format!("Selected {}", (*selected).borrow().selected_element_label));
+            // Undo and redo can destroy the selected element
+            let selected_ele = (*selected).borrow().selected_element.clone().filter(|ele| ele.valid());
+            if selected_ele.is_none() {
+                (*selected).borrow_mut().selected_element = None;
+            }
+            if let Some(mut ele_addr) = selected_ele {
+                let name = (*selected).borrow().selected_element_label.clone();
+                let instance = ele_addr.instance_index().unwrap_or(0);
+                let label = match instance {
+                    0 => name.clone(),
+                    _ => format!("{} #{}", name, instance)
+                };
+                ui.text(format!("Selected {}", label));
                 ui.separator();
 
-                let mut ele_addr = ent_addr.clone().get_ref_mut().unwrap().query_element_addr_by_id(&selected_id);
                 if let Some(mut ele) = ele_addr.get_ref_mut() {
+                    let before = ele.ecs_serialize();
                     ele.fill_ui(ui, man);
+                    if let Some(change) = ElementChange::between(&name, instance, &before, &ele.ecs_serialize()) {
+                        let patch = ScenePatch::entity_modified(&ent_addr, None, None, vec![change]);
+                        history.push(&format!("Edit {}", label), patch, Some(format!("Edit {} {}", truncated_id, label)));
+                    }
                 }
             }
         });
@@ -158,7 +268,7 @@ impl SceneEditor {
         opened
     }
 
-    fn render_ent_recurse(&mut self, ui: &Ui, man: &mut Manager, ent: EntAddr, level: i32) {
+    fn render_ent_recurse(&mut self, ui: &Ui, scene: &mut SceneSerde, man: &mut Manager, ent: EntAddr, level: i32) {
         let cursor = ui.cursor_pos();
         let id_token = ui.push_id(ent.get_ref().unwrap().get_id().as_u128() as i32);
 
@@ -185,13 +295,22 @@ impl SceneEditor {
 
         ui.set_cursor_pos([cursor[0] + 300_f32 + (level * 30) as f32, cursor[1]]);
         if ui.button_with_size(format!("Destroy {}", uuid_truncated(ent.get_ref().unwrap().get_id())), [130_f32, 20_f32]) {
+            let subtree = ent.iter_depth_first().collect::<Vec<EntAddr>>();
+            self.history.push("Destroy entity", ScenePatch::entities_removed(scene, &subtree), None);
             man.destroy_entity(ent.clone());
         }
 
+        ui.set_cursor_pos([cursor[0] + 440_f32 + (level * 30) as f32, cursor[1]]);
+        if ui.button_with_size(format!("Duplicate##{}", ent.get_ref().unwrap().get_id()), [80_f32, 20_f32]) {
+            let copy = man.duplicate_entity(ent.clone(), true);
+            let subtree = copy.iter_depth_first().collect::<Vec<EntAddr>>();
+            self.history.push("Duplicate entity", ScenePatch::entities_added(scene, &subtree), None);
+        }
+
         let children = ent.get_ref().unwrap().get_children();
         if show_children {
             for child in children {
-                self.render_ent_recurse(ui, man, child, level + 1);
+                self.render_ent_recurse(ui, scene, man, child, level + 1);
             }
         }
     }
@@ -205,6 +324,20 @@ impl SceneEditor {
         }
         self.selected_list = new_selected;
 
+        // Edits made while a widget stays active (typing, dragging) merge into one history entry
+        if !ui.is_any_item_active() {
+            self.history.seal();
+        }
+
+        let io = ui.io();
+        if io.key_ctrl && !io.want_text_input {
+            if ui.is_key_pressed(Key::Z) {
+                self.undo(scene, man);
+            } else if ui.is_key_pressed(Key::Y) {
+                self.redo(scene, man);
+            }
+        }
+
         Window::new("Manager")
         .position([0.0, 0.0], Condition::Always)
         .build(ui, || {
@@ -234,11 +367,31 @@ impl SceneEditor {
                 }*/
             }
             if ui.button_with_size("Create Entity", [250_f32, 20_f32]) {
-                man.create_entity(String::new());
+                let ent = man.create_entity(String::new());
+                self.history.push("Create entity", ScenePatch::entities_added(scene, &[ent]), None);
             }
             
             for ent in man.root_entities().iter() {
-                self.render_ent_recurse(ui, man, ent.clone(), 0);
+                self.render_ent_recurse(ui, scene, man, ent.clone(), 0);
+            }
+        });
+
+        Window::new("History")
+        .size([250.0, 300.0], Condition::FirstUseEver)
+        .build(ui, || {
+            if ui.button_with_size("Undo", [100_f32, 20_f32]) {
+                self.undo(scene, man);
+            }
+            ui.same_line();
+            if ui.button_with_size("Redo", [100_f32, 20_f32]) {
+                self.redo(scene, man);
+            }
+            ui.separator();
+            for entry in self.history.undo_entries().iter() {
+                ui.text(&entry.label);
+            }
+            for entry in self.history.redo_entries().iter().rev() {
+                ui.text_disabled(&entry.label);
             }
         });
         
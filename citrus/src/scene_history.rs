@@ -0,0 +1,163 @@
+use crate::entity::*;
+use crate::scene_patch::*;
+use crate::scene_serde::*;
+
+pub struct HistoryEntry {
+    pub label: String,
+    pub patch: ScenePatch,
+    merge_key: Option<String>
+}
+
+// Undo/redo stacks of scene patches
+// Undoing applies the inverse of a patch, so entities that are restored get back their original ids
+#[derive(Default)]
+pub struct SceneHistory {
+    undo_stack: Vec<HistoryEntry>,
+    redo_stack: Vec<HistoryEntry>
+}
+
+impl SceneHistory {
+    pub fn new() -> Self {
+        Self {
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new()
+        }
+    }
+
+    // Records a change that has already been made to the Manager
+    // Consecutive changes with the same merge_key (e.g. typing into one text field) become a single entry
+    pub fn push(&mut self, label: &str, patch: ScenePatch, merge_key: Option<String>) {
+        if patch.is_empty() {
+            return;
+        }
+        self.redo_stack.clear();
+
+        if let Some(top) = self.undo_stack.last_mut() {
+            if merge_key.is_some() && top.merge_key == merge_key {
+                top.patch.entities.extend(patch.entities);
+                return;
+            }
+        }
+
+        self.undo_stack.push(HistoryEntry {
+            label: label.to_string(),
+            patch,
+            merge_key
+        });
+    }
+
+    // Ends merging into the most recent entry
+    pub fn seal(&mut self) {
+        if let Some(top) = self.undo_stack.last_mut() {
+            top.merge_key = None;
+        }
+    }
+
+    // Both return the label of the entry that was undone/redone, or None if there was nothing to do
+    // An entry that no longer applies to the scene is discarded and its error returned
+    pub fn undo(&mut self, scene: &mut SceneSerde, man: &mut Manager) -> Result<Option<String>, SceneSerdeError> {
+        let entry = match self.undo_stack.pop() {
+            Some(entry) => entry,
+            None => return Ok(None)
+        };
+        scene.apply_patch(man, &entry.patch.inverse())?;
+        let label = entry.label.clone();
+        self.redo_stack.push(HistoryEntry { merge_key: None, ..entry });
+        Ok(Some(label))
+    }
+    pub fn redo(&mut self, scene: &mut SceneSerde, man: &mut Manager) -> Result<Option<String>, SceneSerdeError> {
+        let entry = match self.redo_stack.pop() {
+            Some(entry) => entry,
+            None => return Ok(None)
+        };
+        scene.apply_patch(man, &entry.patch)?;
+        let label = entry.label.clone();
+        self.undo_stack.push(entry);
+        Ok(Some(label))
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+    // Oldest first
+    pub fn undo_entries(&self) -> &[HistoryEntry] {
+        &self.undo_stack
+    }
+    // Next to be redone last
+    pub fn redo_entries(&self) -> &[HistoryEntry] {
+        &self.redo_stack
+    }
+    pub fn clear(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+    }
+}
+
+// Patches for the actions the SceneEditor performs
+impl ScenePatch {
+    pub fn entities_added(scene: &mut SceneSerde, ents: &[EntAddr]) -> Self {
+        Self {
+            entities: ents.iter()
+            .map(|ent| {
+                let id = ent.get_ref().unwrap().get_id().to_string();
+                EntityChange::Added { id, entity: scene.snapshot_entity(ent) }
+            })
+            .collect()
+        }
+    }
+    // Must be created before the entities are destroyed
+    pub fn entities_removed(scene: &mut SceneSerde, ents: &[EntAddr]) -> Self {
+        Self::entities_added(scene, ents).inverse()
+    }
+    pub fn entity_modified(ent: &EntAddr, name: Option<(String, String)>, parent: Option<(EntAddr, EntAddr)>, elements: Vec<ElementChange>) -> Self {
+        if name.is_none() && parent.is_none() && elements.is_empty() {
+            return Self::default();
+        }
+        let id_of = |addr: &EntAddr| addr.get_ref().map(|ent| ent.get_id().to_string());
+        Self {
+            entities: vec![EntityChange::Modified {
+                id: id_of(ent).unwrap(),
+                name,
+                parent: parent.map(|(before, after)| (id_of(&before), id_of(&after))),
+                elements,
+                active: None,
+                tags: None,
+                layers: None
+            }]
+        }
+    }
+    pub fn entity_activated(ent: &EntAddr, before: bool, after: bool) -> Self {
+        match before == after {
+            true => Self::default(),
+            false => Self::entity_flags(ent, Some((before, after)), None, None)
+        }
+    }
+    pub fn entity_tagged(ent: &EntAddr, before: Vec<String>, after: Vec<String>) -> Self {
+        match before == after {
+            true => Self::default(),
+            false => Self::entity_flags(ent, None, Some((before, after)), None)
+        }
+    }
+    pub fn entity_layers_changed(ent: &EntAddr, before: u32, after: u32) -> Self {
+        match before == after {
+            true => Self::default(),
+            false => Self::entity_flags(ent, None, None, Some((before, after)))
+        }
+    }
+    fn entity_flags(ent: &EntAddr, active: Option<(bool, bool)>, tags: Option<(Vec<String>, Vec<String>)>, layers: Option<(u32, u32)>) -> Self {
+        Self {
+            entities: vec![EntityChange::Modified {
+                id: ent.get_ref().unwrap().get_id().to_string(),
+                name: None,
+                parent: None,
+                elements: vec![],
+                active,
+                tags,
+                layers
+            }]
+        }
+    }
+}
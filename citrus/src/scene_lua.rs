@@ -0,0 +1,279 @@
+use std::{cell::RefCell, fs, sync::Mutex};
+use mlua::{Function, Lua, Table, Value};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::borrow::*;
+use crate::deserialize_context::*;
+use crate::element::*;
+use crate::entity::*;
+
+// Where a LuaScript's code comes from
+#[derive(Clone, Serialize, Deserialize)]
+pub enum LuaSource {
+    Inline(String),
+    Path(String)
+}
+
+// The interpreter is never cloned or serialized, a copy of a LuaScript loads its own on first update
+// With the sync feature mlua's send feature makes Lua Send, and the Mutex makes the runtime Sync as elements have to be
+#[derive(Default)]
+struct LuaRuntime(Mutex<Option<Lua>>);
+
+impl Clone for LuaRuntime {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+// Runs the global Lua function update(self_id) every Manager::update
+//
+// During update the global table "citrus" exposes the Manager, with entities referred to by their id strings:
+//   citrus.create_entity(name) -> id           citrus.destroy_entity(id)
+//   citrus.reparent(child, parent|nil) -> bool citrus.get_parent(id) -> id|nil
+//   citrus.get_children(id) -> {id}            citrus.get_name(id) / citrus.set_name(id, name)
+//   citrus.find_by_path(path, from|nil) -> id|nil  citrus.get_path(id) -> path
+//   citrus.get_element(id, type) -> table|nil  citrus.set_element(id, type, table)
+// Element types are named by their Rust type name, with or without the module path,
+// and their fields are read and written through ecs_serialize/ecs_deserialize
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LuaScript {
+    pub source: LuaSource,
+    #[serde(skip)]
+    runtime: LuaRuntime,
+    #[serde(skip)]
+    last_error: Option<String>
+}
+
+impl LuaScript {
+    pub fn new(source: LuaSource) -> Self {
+        Self {
+            source,
+            runtime: LuaRuntime::default(),
+            last_error: None
+        }
+    }
+    pub fn inline(code: &str) -> Self {
+        Self::new(LuaSource::Inline(code.to_string()))
+    }
+    pub fn path(path: &str) -> Self {
+        Self::new(LuaSource::Path(path.to_string()))
+    }
+
+    // Discards the interpreter state; the source is loaded again on the next update
+    pub fn reload(&mut self) {
+        self.runtime = LuaRuntime::default();
+        self.last_error = None;
+    }
+    // The error from the most recent load or update, if it failed
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    fn runtime(&mut self) -> Result<&Lua, String> {
+        let runtime = self.runtime.0.get_mut().unwrap();
+        if runtime.is_none() {
+            let (code, name) = match &self.source {
+                LuaSource::Inline(code) => (code.clone(), "inline script".to_string()),
+                LuaSource::Path(path) => (fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?, path.clone())
+            };
+            let lua = Lua::new();
+            lua.load(&code).set_name(name).exec().map_err(|err| err.to_string())?;
+            *runtime = Some(lua);
+        }
+        Ok(runtime.as_ref().unwrap())
+    }
+
+    fn run_update(&mut self, man: &mut Manager, owner: EntAddr) -> Result<(), String> {
+        let lua = self.runtime()?;
+        let update = match lua.globals().get::<_, Option<Function>>("update").map_err(|err| err.to_string())? {
+            Some(update) => update,
+            None => return Ok(())
+        };
+
+        let man = RefCell::new(man);
+        lua.scope(|scope| {
+            let api = lua.create_table()?;
+            api.set("create_entity", scope.create_function(|_, name: Option<String>| {
+                Ok(entity_id(&man.borrow_mut().create_entity(name.unwrap_or_default())))
+            })?)?;
+            api.set("destroy_entity", scope.create_function(|_, id: String| {
+                let ent = find_entity(&man.borrow(), &id)?;
+                man.borrow_mut().destroy_entity(ent);
+                Ok(())
+            })?)?;
+            api.set("reparent", scope.create_function(|_, (child, parent): (String, Option<String>)| {
+                let child = find_entity(&man.borrow(), &child)?;
+                let parent = match parent {
+                    Some(parent) => find_entity(&man.borrow(), &parent)?,
+                    None => EntAddr::new()
+                };
+                Ok(man.borrow_mut().reparent(child, parent).is_ok())
+            })?)?;
+            api.set("get_parent", scope.create_function(|_, id: String| {
+                Ok(entity_id(&find_entity(&man.borrow(), &id)?.get_ref().unwrap().get_parent()))
+            })?)?;
+            api.set("get_children", scope.create_function(|_, id: String| {
+                let children = find_entity(&man.borrow(), &id)?.get_ref().unwrap().get_children();
+                Ok(children.iter().filter_map(entity_id).collect::<Vec<String>>())
+            })?)?;
+            api.set("get_name", scope.create_function(|_, id: String| {
+                Ok(find_entity(&man.borrow(), &id)?.get_ref().unwrap().name.clone())
+            })?)?;
+            api.set("set_name", scope.create_function(|_, (id, name): (String, String)| {
+                find_entity(&man.borrow(), &id)?.get_ref_mut().unwrap().name = name;
+                Ok(())
+            })?)?;
+            api.set("find_by_path", scope.create_function(|_, (path, from): (String, Option<String>)| {
+                let from = match from {
+                    Some(from) => find_entity(&man.borrow(), &from)?,
+                    None => EntAddr::new()
+                };
+                Ok(entity_id(&man.borrow().find_relative(&from, &path)))
+            })?)?;
+            api.set("get_path", scope.create_function(|_, id: String| {
+                Ok(find_entity(&man.borrow(), &id)?.path())
+            })?)?;
+            api.set("get_element", scope.create_function(|lua, (id, type_name): (String, String)| {
+                let ele = find_element(&find_entity(&man.borrow(), &id)?, &type_name);
+                // The script's own element is borrowed while it runs
+                match ele.try_get_ref() {
+                    Ok(ele) => json_to_lua(lua, &ele.ecs_serialize()),
+                    Err(BorrowError::Dead(_)) => Ok(Value::Nil),
+                    Err(err) => Err(mlua::Error::RuntimeError(err.to_string()))
+                }
+            })?)?;
+            api.set("set_element", scope.create_function(|_, (id, type_name, val): (String, String, Value)| {
+                let mut ele = find_element(&find_entity(&man.borrow(), &id)?, &type_name);
+                if !ele.valid() {
+                    return Err(mlua::Error::RuntimeError(format!("Entity {} has no element \"{}\"", id, type_name)));
+                }
+                let mut ele = ele.try_get_ref_mut().map_err(|err| mlua::Error::RuntimeError(err.to_string()))?;
+                let payload = lua_to_json(&val, &ele.ecs_serialize())?;
+                let context = DeserializeContext::for_payload(&man.borrow(), &payload);
+                context.scope(|| ele.ecs_deserialize(payload))
+                .map_err(|err| mlua::Error::RuntimeError(err.to_string()))
+            })?)?;
+            lua.globals().set("citrus", api)?;
+
+            update.call::<_, ()>(entity_id(&owner))
+        })
+        .map_err(|err| err.to_string())
+    }
+}
+
+impl Element for LuaScript {
+    // Several scripts can run on one entity
+    fn multi_instance() -> bool {
+        true
+    }
+    fn update(&mut self, man: &mut Manager, owner: EntAddr) {
+        self.last_error = self.run_update(man, owner).err();
+    }
+    #[cfg(feature = "gen-imgui")]
+    fn fill_ui(&mut self, ui: &imgui::Ui, _man: &mut Manager) {
+        match &mut self.source {
+            LuaSource::Inline(code) => { ui.input_text_multiline(":Source", code, [380_f32, 200_f32]).build(); },
+            LuaSource::Path(path) => { ui.input_text(":Path", path).build(); }
+        }
+        if ui.button_with_size("Reload", [200_f32, 20_f32]) {
+            self.reload();
+        }
+        if let Some(err) = &self.last_error {
+            ui.text_wrapped(err);
+        }
+    }
+}
+
+fn entity_id(addr: &EntAddr) -> Option<String> {
+    addr.get_ref().map(|ent| ent.get_id().to_string())
+}
+
+fn find_entity(man: &Manager, id: &str) -> mlua::Result<EntAddr> {
+    let ent = Uuid::parse_str(id).map(|id| man.find_by_id(id)).unwrap_or_else(|_| EntAddr::new());
+    match ent.valid() {
+        true => Ok(ent),
+        false => Err(mlua::Error::RuntimeError(format!("No entity with id \"{}\"", id)))
+    }
+}
+
+// Matches either the full type name or its last path segment
+fn find_element(ent: &EntAddr, type_name: &str) -> EleAddrErased {
+    ent.get_ref_mut().unwrap()
+    .erased_elements()
+    .into_iter()
+    .find(|ele| {
+        let full = ele.get_element_type_name().unwrap();
+        full == type_name || full.rsplit("::").next() == Some(type_name)
+    })
+    .unwrap_or_else(EleAddrErased::new)
+}
+
+fn json_to_lua<'lua>(lua: &'lua Lua, val: &serde_json::Value) -> mlua::Result<Value<'lua>> {
+    Ok(match val {
+        serde_json::Value::Null => Value::Nil,
+        serde_json::Value::Bool(b) => Value::Boolean(*b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Number(n.as_f64().unwrap_or_default())
+        },
+        serde_json::Value::String(st) => Value::String(lua.create_string(st)?),
+        serde_json::Value::Array(arr) => {
+            let table = lua.create_table()?;
+            for (i, v) in arr.iter().enumerate() {
+                table.raw_set(i + 1, json_to_lua(lua, v)?)?;
+            }
+            Value::Table(table)
+        },
+        serde_json::Value::Object(obj) => {
+            let table = lua.create_table()?;
+            for (k, v) in obj.iter() {
+                table.raw_set(k.as_str(), json_to_lua(lua, v)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+// Tables with only the keys 1..n become arrays, all other tables become objects
+// like is the value being replaced; an empty table only becomes an array where like is one
+fn lua_to_json(val: &Value, like: &serde_json::Value) -> mlua::Result<serde_json::Value> {
+    Ok(match val {
+        Value::Nil => serde_json::Value::Null,
+        Value::Boolean(b) => serde_json::Value::Bool(*b),
+        Value::Integer(i) => serde_json::Value::from(*i),
+        // integral floats are written as integers so that they still deserialize into integer fields
+        Value::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => serde_json::Value::from(*n as i64),
+        Value::Number(n) => serde_json::Number::from_f64(*n).map_or(serde_json::Value::Null, serde_json::Value::Number),
+        Value::String(st) => serde_json::Value::String(st.to_str()?.to_string()),
+        Value::Table(table) => table_to_json(table, like)?,
+        other => return Err(mlua::Error::RuntimeError(format!("Can't convert a Lua {} to element data", other.type_name())))
+    })
+}
+
+fn table_to_json(table: &Table, like: &serde_json::Value) -> mlua::Result<serde_json::Value> {
+    let len = table.raw_len();
+    let pairs = table.clone().pairs::<Value, Value>().collect::<mlua::Result<Vec<(Value, Value)>>>()?;
+
+    if pairs.is_empty() && !like.is_array() {
+        return Ok(serde_json::Value::Object(serde_json::Map::new()));
+    }
+    if pairs.len() == len {
+        let arr = (1..=len)
+        .map(|i| lua_to_json(&table.raw_get::<_, Value>(i)?, like.get(i - 1).unwrap_or(&serde_json::Value::Null)))
+        .collect::<mlua::Result<Vec<serde_json::Value>>>()?;
+        return Ok(serde_json::Value::Array(arr));
+    }
+
+    let mut obj = serde_json::Map::new();
+    for (k, v) in pairs.iter() {
+        let key = match k {
+            Value::String(st) => st.to_str()?.to_string(),
+            Value::Integer(i) => i.to_string(),
+            other => return Err(mlua::Error::RuntimeError(format!("Can't use a Lua {} as an element field name", other.type_name())))
+        };
+        let val = lua_to_json(v, like.get(&key).unwrap_or(&serde_json::Value::Null))?;
+        obj.insert(key, val);
+    }
+    Ok(serde_json::Value::Object(obj))
+}
@@ -0,0 +1,547 @@
+use std::collections::{HashMap, HashSet};
+use serde::*;
+use uuid::Uuid;
+
+use crate::deserialize_context::*;
+use crate::element::*;
+use crate::entity::*;
+use crate::scene_serde::*;
+
+// The difference between two serialized scenes, keyed by entity id and element creator name
+// Every change records both its before and after state so a patch can be inverted
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
+pub struct ScenePatch {
+    pub entities: Vec<EntityChange>
+}
+
+// A single JSON value that changed, at a JSON pointer path (e.g. "/pos/1") into the element's ecs_serialize payload
+// None means the field doesn't exist on that side
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub path: String,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>
+}
+
+// instance is the position among the entity's elements of that type (nonzero only for multi_instance types),
+// before the change for Removed, Modified and Enabled and after it for Added
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ElementChange {
+    Added {
+        name: String,
+        #[serde(default)] instance: usize,
+        payload: serde_json::Value,
+        #[serde(default = "default_true", skip_serializing_if = "is_true")] enabled: bool
+    },
+    Removed {
+        name: String,
+        #[serde(default)] instance: usize,
+        payload: serde_json::Value,
+        #[serde(default = "default_true", skip_serializing_if = "is_true")] enabled: bool
+    },
+    Modified { name: String, #[serde(default)] instance: usize, fields: Vec<FieldChange> },
+    Enabled { name: String, #[serde(default)] instance: usize, enabled: (bool, bool) }
+}
+
+// Parent ids are None for root entities
+// disabled holds the indices into eles of the disabled elements
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EntitySnapshot {
+    pub name: String,
+    pub parent: Option<String>,
+    pub eles: Vec<(String, serde_json::Value)>,
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    pub active: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub disabled: Vec<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default = "default_layers", skip_serializing_if = "is_default_layers")]
+    pub layers: u32
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EntityChange {
+    Added { id: String, entity: EntitySnapshot },
+    Removed { id: String, entity: EntitySnapshot },
+    Modified {
+        id: String,
+        name: Option<(String, String)>,
+        parent: Option<(Option<String>, Option<String>)>,
+        elements: Vec<ElementChange>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        active: Option<(bool, bool)>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tags: Option<(Vec<String>, Vec<String>)>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        layers: Option<(u32, u32)>
+    }
+}
+
+impl FieldChange {
+    pub fn inverse(&self) -> Self {
+        Self {
+            path: self.path.clone(),
+            before: self.after.clone(),
+            after: self.before.clone()
+        }
+    }
+}
+
+impl ElementChange {
+    pub fn inverse(&self) -> Self {
+        match self {
+            ElementChange::Added { name, instance, payload, enabled } => ElementChange::Removed { name: name.clone(), instance: *instance, payload: payload.clone(), enabled: *enabled },
+            ElementChange::Removed { name, instance, payload, enabled } => ElementChange::Added { name: name.clone(), instance: *instance, payload: payload.clone(), enabled: *enabled },
+            ElementChange::Modified { name, instance, fields } => ElementChange::Modified {
+                name: name.clone(),
+                instance: *instance,
+                fields: fields.iter().map(|field| field.inverse()).collect()
+            },
+            ElementChange::Enabled { name, instance, enabled: (before, after) } => ElementChange::Enabled {
+                name: name.clone(),
+                instance: *instance,
+                enabled: (*after, *before)
+            }
+        }
+    }
+}
+
+impl ElementChange {
+    // A Modified change from one ecs_serialize payload to another, or None if they are equal
+    pub fn between(name: &str, instance: usize, before: &serde_json::Value, after: &serde_json::Value) -> Option<Self> {
+        let mut fields = Vec::new();
+        diff_values(String::new(), before, after, &mut fields);
+        match fields.is_empty() {
+            true => None,
+            false => Some(ElementChange::Modified { name: name.to_string(), instance, fields })
+        }
+    }
+}
+
+impl EntityChange {
+    pub fn id(&self) -> &str {
+        match self {
+            EntityChange::Added { id, .. } => id,
+            EntityChange::Removed { id, .. } => id,
+            EntityChange::Modified { id, .. } => id
+        }
+    }
+    pub fn inverse(&self) -> Self {
+        match self {
+            EntityChange::Added { id, entity } => EntityChange::Removed { id: id.clone(), entity: entity.clone() },
+            EntityChange::Removed { id, entity } => EntityChange::Added { id: id.clone(), entity: entity.clone() },
+            EntityChange::Modified { id, name, parent, elements, active, tags, layers } => EntityChange::Modified {
+                id: id.clone(),
+                name: name.as_ref().map(|(before, after)| (after.clone(), before.clone())),
+                parent: parent.as_ref().map(|(before, after)| (after.clone(), before.clone())),
+                elements: elements.iter().map(|ele| ele.inverse()).collect(),
+                active: active.map(|(before, after)| (after, before)),
+                tags: tags.as_ref().map(|(before, after)| (after.clone(), before.clone())),
+                layers: layers.map(|(before, after)| (after, before))
+            }
+        }
+    }
+}
+
+impl ScenePatch {
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+    // Applying the inverse of a patch undoes it
+    pub fn inverse(&self) -> Self {
+        Self {
+            entities: self.entities.iter().rev().map(|change| change.inverse()).collect()
+        }
+    }
+}
+
+fn snapshot(ent: &SceneEntObj) -> Result<(String, EntitySnapshot), SceneSerdeError> {
+    let to_id = |id: &SerializedEntId| id.to_id().map_err(|er| SceneSerdeError::SerdeError(serde::de::Error::custom(er)));
+
+    let parent = serde_json::from_value::<Option<SerializedEntId>>(ent.parent_payload.clone())
+    .map_err(SceneSerdeError::SerdeError)?
+    .map(|parent| to_id(&parent))
+    .transpose()?
+    .filter(|parent| !parent.is_nil())
+    .map(|parent| parent.to_string());
+
+    Ok((to_id(&ent.id)?.to_string(), EntitySnapshot {
+        name: ent.name.clone(),
+        parent,
+        eles: ent.eles.iter().map(|ele| (ele.name.clone(), ele.payload.clone())).collect(),
+        active: ent.active,
+        disabled: ent.eles.iter().enumerate().filter(|(_, ele)| !ele.enabled).map(|(i, _)| i).collect(),
+        tags: ent.tags.clone(),
+        layers: ent.layers
+    }))
+}
+
+fn escape_pointer_token(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+fn diff_values(path: String, before: &serde_json::Value, after: &serde_json::Value, res: &mut Vec<FieldChange>) {
+    match (before, after) {
+        (serde_json::Value::Object(b), serde_json::Value::Object(a)) => {
+            for (key, bv) in b.iter() {
+                let sub_path = format!("{}/{}", path, escape_pointer_token(key));
+                match a.get(key) {
+                    Some(av) => diff_values(sub_path, bv, av, res),
+                    None => res.push(FieldChange { path: sub_path, before: Some(bv.clone()), after: None })
+                }
+            }
+            for (key, av) in a.iter().filter(|(key, _)| !b.contains_key(*key)) {
+                res.push(FieldChange { path: format!("{}/{}", path, escape_pointer_token(key)), before: None, after: Some(av.clone()) });
+            }
+        },
+        (serde_json::Value::Array(b), serde_json::Value::Array(a)) if a.len() == b.len() => {
+            for (i, (bv, av)) in b.iter().zip(a.iter()).enumerate() {
+                diff_values(format!("{}/{}", path, i), bv, av, res);
+            }
+        },
+        _ => if before != after {
+            res.push(FieldChange { path, before: Some(before.clone()), after: Some(after.clone()) });
+        }
+    }
+}
+
+// Applies field changes to an ecs_serialize payload
+fn patch_value(payload: &mut serde_json::Value, fields: &[FieldChange]) -> Result<(), String> {
+    for field in fields.iter() {
+        if field.path.is_empty() {
+            *payload = field.after.clone().unwrap_or(serde_json::Value::Null);
+            continue;
+        }
+
+        let split = field.path.rfind('/')
+        .ok_or_else(|| format!("Field path \"{}\" isn't a JSON pointer", field.path))?;
+        let key = field.path[split + 1..].replace("~1", "/").replace("~0", "~");
+        let parent = payload.pointer_mut(&field.path[..split])
+        .ok_or_else(|| format!("Field \"{}\" doesn't exist", field.path))?;
+
+        match (parent, &field.after) {
+            (serde_json::Value::Object(obj), Some(after)) => { obj.insert(key, after.clone()); },
+            (serde_json::Value::Object(obj), None) => { obj.remove(&key); },
+            (serde_json::Value::Array(arr), Some(after)) => {
+                let slot = key.parse::<usize>().ok().and_then(|i| arr.get_mut(i))
+                .ok_or_else(|| format!("Field \"{}\" doesn't exist", field.path))?;
+                *slot = after.clone();
+            },
+            _ => return Err(format!("Field \"{}\" can't be changed", field.path))
+        }
+    }
+    Ok(())
+}
+
+fn patch_error(info: String) -> SceneSerdeError {
+    SceneSerdeError::PatchError(info)
+}
+
+fn find_instance(scene: &SceneSerde, ent: &EntAddr, name: &str, instance: usize) -> Result<EleAddrErased, SceneSerdeError> {
+    scene.find_exact_creator(name)
+    .and_then(|entry| ent.get_ref_mut().unwrap().query_elements_by_id(&entry.id).into_iter().nth(instance))
+    .ok_or_else(|| SceneSerdeError::MissingElementError(name.to_string()))
+}
+
+// Payloads of the elements named name, in instance order, along with whether they're enabled
+fn instances<'a>(ent: &'a EntitySnapshot, name: &str) -> Vec<(&'a serde_json::Value, bool)> {
+    ent.eles.iter()
+    .enumerate()
+    .filter(|(_, (other, _))| other == name)
+    .map(|(i, (_, payload))| (payload, !ent.disabled.contains(&i)))
+    .collect()
+}
+
+impl SceneSerde {
+    // The current state of a live entity, in the form recorded by patches
+    pub fn snapshot_entity(&mut self, ent: &EntAddr) -> EntitySnapshot {
+        let eles = ent.get_ref_mut().unwrap().erased_elements();
+        let ent_ref = ent.get_ref().unwrap();
+        let eles = eles.iter()
+        .filter_map(|ele| {
+            let name = self.find_exact_creator_by_id(ele.get_element_type_id()?)?.name;
+            Some((name, ele.get_ref()?.ecs_serialize(), ele.is_enabled()))
+        })
+        .collect::<Vec<(String, serde_json::Value, bool)>>();
+        EntitySnapshot {
+            name: ent_ref.name.clone(),
+            parent: ent_ref.get_parent().get_ref().map(|parent| parent.get_id().to_string()),
+            active: ent_ref.is_active_self(),
+            disabled: eles.iter().enumerate().filter(|(_, (_, _, enabled))| !enabled).map(|(i, _)| i).collect(),
+            tags: ent_ref.get_tags().to_vec(),
+            layers: ent_ref.get_layers(),
+            eles: eles.into_iter().map(|(name, payload, _)| (name, payload)).collect()
+        }
+    }
+
+    // Compares two serialized scenes, as produced by serialize_scene
+    pub fn diff(before: &serde_json::Value, after: &serde_json::Value) -> Result<ScenePatch, SceneSerdeError> {
+        let before = parse_scene(before.clone())?.iter().map(snapshot).collect::<Result<Vec<_>, _>>()?;
+        let after = parse_scene(after.clone())?.iter().map(snapshot).collect::<Result<Vec<_>, _>>()?;
+        let before_map: HashMap<&String, &EntitySnapshot> = before.iter().map(|(id, ent)| (id, ent)).collect();
+        let after_map: HashMap<&String, &EntitySnapshot> = after.iter().map(|(id, ent)| (id, ent)).collect();
+
+        let mut entities = Vec::new();
+
+        for (id, b) in before.iter().filter(|(id, _)| !after_map.contains_key(id)) {
+            entities.push(EntityChange::Removed { id: id.clone(), entity: b.clone() });
+        }
+
+        for (id, a) in after.iter() {
+            let b = match before_map.get(id) {
+                Some(b) => b,
+                None => {
+                    entities.push(EntityChange::Added { id: id.clone(), entity: a.clone() });
+                    continue;
+                }
+            };
+
+            // Instances of a type are matched up by position
+            let mut elements = Vec::new();
+            let mut names = Vec::<&String>::new();
+            b.eles.iter().chain(a.eles.iter()).for_each(|(name, _)| if !names.contains(&name) { names.push(name) });
+            for name in names.into_iter() {
+                let (before_eles, after_eles) = (instances(b, name), instances(a, name));
+                for (instance, (payload, enabled)) in before_eles.iter().enumerate().skip(after_eles.len()) {
+                    elements.push(ElementChange::Removed { name: name.clone(), instance, payload: (*payload).clone(), enabled: *enabled });
+                }
+                for (instance, ((before_payload, before_enabled), (payload, enabled))) in before_eles.iter().zip(after_eles.iter()).enumerate() {
+                    elements.extend(ElementChange::between(name, instance, before_payload, payload));
+                    if before_enabled != enabled {
+                        elements.push(ElementChange::Enabled { name: name.clone(), instance, enabled: (*before_enabled, *enabled) });
+                    }
+                }
+                for (instance, (payload, enabled)) in after_eles.iter().enumerate().skip(before_eles.len()) {
+                    elements.push(ElementChange::Added { name: name.clone(), instance, payload: (*payload).clone(), enabled: *enabled });
+                }
+            }
+
+            let name = match b.name != a.name {
+                true => Some((b.name.clone(), a.name.clone())),
+                false => None
+            };
+            let parent = match b.parent != a.parent {
+                true => Some((b.parent.clone(), a.parent.clone())),
+                false => None
+            };
+            let active = match b.active != a.active {
+                true => Some((b.active, a.active)),
+                false => None
+            };
+            let tags = match b.tags != a.tags {
+                true => Some((b.tags.clone(), a.tags.clone())),
+                false => None
+            };
+            let layers = match b.layers != a.layers {
+                true => Some((b.layers, a.layers)),
+                false => None
+            };
+
+            if name.is_some() || parent.is_some() || active.is_some() || tags.is_some() || layers.is_some() || !elements.is_empty() {
+                entities.push(EntityChange::Modified { id: id.clone(), name, parent, elements, active, tags, layers });
+            }
+        }
+
+        Ok(ScenePatch { entities })
+    }
+
+    // Applies a patch to the live entities of man, matched by id
+    // Fails without changing anything if an entity or parent the patch refers to is missing, or an added one is already present,
+    // otherwise returns the added entities along with any errors from individual elements
+    pub fn apply_patch(&mut self, man: &mut Manager, patch: &ScenePatch) -> Result<SceneDeserResult, SceneSerdeError> {
+        let find = |man: &Manager, id: &str| -> Result<EntAddr, SceneSerdeError> {
+            let ent = Uuid::parse_str(id).map(|id| man.find_by_id(id)).unwrap_or_else(|_| EntAddr::new());
+            match ent.valid() {
+                true => Ok(ent),
+                false => Err(patch_error(format!("Patched entity {} doesn't exist", id)))
+            }
+        };
+        let find_parent = |man: &Manager, id: &Option<String>| match id {
+            Some(id) => find(man, id),
+            None => Ok(EntAddr::new())
+        };
+
+        // Validate up front so a patch that doesn't fit this scene changes nothing
+        let mut added_ids = HashSet::new();
+        for change in patch.entities.iter() {
+            match change {
+                EntityChange::Added { id, .. } => {
+                    let parsed = Uuid::parse_str(id).map_err(|er| patch_error(format!("Invalid entity id \"{}\": {}", id, er)))?;
+                    if parsed.is_nil() {
+                        return Err(patch_error(format!("Invalid entity id \"{}\"", id)));
+                    }
+                    if man.find_by_id(parsed).valid() || !added_ids.insert(id.as_str()) {
+                        return Err(patch_error(format!("Added entity {} already exists", id)));
+                    }
+                },
+                EntityChange::Removed { id, .. } | EntityChange::Modified { id, .. } => { find(man, id)?; }
+            }
+        }
+        for change in patch.entities.iter() {
+            let parent = match change {
+                EntityChange::Added { entity, .. } => &entity.parent,
+                EntityChange::Modified { parent: Some((_, parent)), .. } => parent,
+                _ => continue
+            };
+            if let Some(parent) = parent.as_deref().filter(|parent| !added_ids.contains(parent)) {
+                find(man, parent)?;
+            }
+        }
+
+        let mut added = Vec::new();
+        for change in patch.entities.iter() {
+            if let EntityChange::Added { id, entity } = change {
+                let ent = man.create_entity_with_id(entity.name.clone(), Uuid::parse_str(id).unwrap()).map_err(patch_error)?;
+                {
+                    let mut ent_ref = ent.get_ref_mut().unwrap();
+                    ent_ref.set_active(entity.active);
+                    ent_ref.set_tags(entity.tags.clone());
+                    ent_ref.set_layers(entity.layers);
+                }
+                added.push(ent);
+            }
+        }
+
+        // Hierarchy changes, now that every entity exists
+        let mut reparent_failures = Vec::<String>::new();
+        let mut reparented = Vec::new();
+        for change in patch.entities.iter() {
+            let (id, parent) = match change {
+                EntityChange::Added { id, entity } => (id, &entity.parent),
+                EntityChange::Modified { id, parent: Some((_, parent)), .. } => (id, parent),
+                _ => continue
+            };
+            let child = find(man, id)?;
+            let parent = find_parent(man, parent)?;
+            let old_parent = child.get_ref().unwrap().get_parent();
+            match man.reparent(child.clone(), parent.clone()) {
+                Ok(()) => reparented.push((child, old_parent)),
+                Err(_) => reparent_failures.push(format!("Making Child -> Parent relationship \"{}\" -> \"{}\" would have created a cycle", child.get_ref().unwrap().name, parent.get_ref().unwrap().name))
+            }
+        }
+        if !reparent_failures.is_empty() {
+            // Undo in reverse so every step returns to a hierarchy that already existed
+            reparented.into_iter().rev().for_each(|(child, old_parent)| {
+                man.reparent(child, old_parent).unwrap();
+            });
+            added.into_iter().for_each(|ent| man.destroy_entity(ent));
+            man.resolve();
+            return Err(SceneSerdeError::CycleError(reparent_failures.join("\n")));
+        }
+
+        // Element changes; payloads are deserialized last so that EleAddrs can find newly added elements
+        let mut errors = Vec::new();
+        let mut payloads = Vec::new();
+        for change in patch.entities.iter() {
+            let (ent, ele_changes) = match change {
+                EntityChange::Added { id, entity } => {
+                    let changes = entity.eles.iter()
+                    .enumerate()
+                    .map(|(i, (name, payload))| {
+                        let instance = entity.eles[..i].iter().filter(|(other, _)| other == name).count();
+                        ElementChange::Added { name: name.clone(), instance, payload: payload.clone(), enabled: !entity.disabled.contains(&i) }
+                    })
+                    .collect::<Vec<ElementChange>>();
+                    (find(man, id)?, changes)
+                },
+                EntityChange::Modified { id, name, elements, active, tags, layers, .. } => {
+                    let ent = find(man, id)?;
+                    if let Some((_, name)) = name {
+                        ent.get_ref_mut().unwrap().name = name.clone();
+                    }
+                    if let Some((_, active)) = active {
+                        ent.get_ref_mut().unwrap().set_active(*active);
+                    }
+                    if let Some((_, tags)) = tags {
+                        ent.get_ref_mut().unwrap().set_tags(tags.clone());
+                    }
+                    if let Some((_, layers)) = layers {
+                        ent.get_ref_mut().unwrap().set_layers(*layers);
+                    }
+                    (ent, elements.clone())
+                },
+                EntityChange::Removed { .. } => continue
+            };
+
+            // Instances are looked up before any element is removed, so they refer to the state before this change
+            let mut removed = Vec::new();
+            let mut added_eles = Vec::new();
+            for ele_change in ele_changes.into_iter() {
+                match ele_change {
+                    ElementChange::Added { name, instance, payload, enabled } => added_eles.push((name, instance, payload, enabled)),
+                    ElementChange::Removed { name, instance, .. } => {
+                        match find_instance(self, &ent, &name, instance) {
+                            Ok(ele) => removed.push(ele),
+                            Err(err) => errors.push(err)
+                        }
+                    },
+                    ElementChange::Modified { name, instance, fields } => {
+                        let ele = match find_instance(self, &ent, &name, instance) {
+                            Ok(ele) => ele,
+                            Err(err) => {
+                                errors.push(err);
+                                continue;
+                            }
+                        };
+                        let mut payload = ele.get_ref().unwrap().ecs_serialize();
+                        match patch_value(&mut payload, &fields) {
+                            Ok(()) => payloads.push((ele, payload)),
+                            Err(err) => errors.push(patch_error(format!("{}: {}", name, err)))
+                        }
+                    },
+                    ElementChange::Enabled { name, instance, enabled: (_, enabled) } => {
+                        match find_instance(self, &ent, &name, instance) {
+                            Ok(ele) => ele.set_enabled(enabled),
+                            Err(err) => errors.push(err)
+                        }
+                    }
+                }
+            }
+
+            if !removed.is_empty() {
+                removed.into_iter().for_each(|ele| man.destroy_element(ele));
+                man.resolve();
+            }
+
+            // Added in instance order so each one can be moved into its place
+            added_eles.sort_by_key(|(_, instance, _, _)| *instance);
+            for (name, instance, payload, enabled) in added_eles.into_iter() {
+                let present = self.find_exact_creator(&name)
+                .is_some_and(|entry| !entry.multi_instance && ent.get_ref_mut().unwrap().query_element_addr_by_id(&entry.id).valid());
+                if present {
+                    errors.push(patch_error(format!("Added element \"{}\" is already present", name)));
+                    continue;
+                }
+                match self.deserialize_empty_into(ent.clone(), name) {
+                    Ok(ele) => {
+                        ele.set_enabled(enabled);
+                        ent.get_ref_mut().unwrap().move_element_instance(&ele, instance);
+                        payloads.push((ele, payload));
+                    },
+                    Err(err) => errors.push(err)
+                }
+            }
+        }
+
+        for (mut ele, payload) in payloads.into_iter() {
+            let context = DeserializeContext::for_payload(man, &payload);
+            if let Err(err) = context.scope(|| ele.get_ref_mut().unwrap().ecs_deserialize(payload)) {
+                errors.push(SceneSerdeError::SerdeError(err));
+            }
+        }
+
+        for change in patch.entities.iter() {
+            if let EntityChange::Removed { id, .. } = change {
+                let ent = find(man, id)?;
+                man.destroy_entity(ent);
+            }
+        }
+        man.resolve();
+
+        Ok(SceneDeserResult {
+            ents: added,
+            errors
+        })
+    }
+}
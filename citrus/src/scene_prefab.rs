@@ -0,0 +1,55 @@
+use serde::*;
+
+use crate::entity::*;
+use crate::scene_serde::*;
+
+// An entity and its descendants saved in the scene format, root first
+// Serializes as that scene, so prefabs can be written to and read from their own files
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Prefab {
+    pub content: serde_json::Value
+}
+
+impl Prefab {
+    pub fn new(content: serde_json::Value) -> Self {
+        Self { content }
+    }
+}
+
+impl SceneSerde {
+    // None if root is dead
+    pub fn create_prefab(&mut self, man: &mut Manager, root: EntAddr) -> Option<Prefab> {
+        if !root.valid() {
+            return None;
+        }
+        let mut content = self.serialize_scene(man, root.iter_depth_first().collect());
+        // The root's parent isn't part of the prefab, instances get theirs from instantiate_prefab
+        content["entities"].get_mut(0)?["parent_payload"] = serde_json::Value::Null;
+        Some(Prefab::new(content))
+    }
+
+    // Creates a copy of the prefab under parent (or as a root entity if parent is invalid) and returns its root
+    // Every instance gets fresh entity ids; references between entities of the prefab point to the new copies,
+    // references to other entities point to the live entities of man with those ids
+    // If any element fails to load, the instance is destroyed again and the first error returned
+    pub fn instantiate_prefab(&mut self, man: &mut Manager, prefab: &Prefab, parent: EntAddr) -> Result<EntAddr, SceneSerdeError> {
+        let ent_objs = parse_scene(prefab.content.clone())?;
+        if ent_objs.is_empty() {
+            return Err(SceneSerdeError::SerdeError(serde::de::Error::custom("Prefab contains no entities")));
+        }
+
+        let res = self.deserialize_ent_objs(man, ent_objs, true)?;
+        let root = res.ents[0].clone();
+
+        if let Some(err) = res.errors.into_iter().next() {
+            res.ents.into_iter().for_each(|ent| man.destroy_entity(ent));
+            man.resolve();
+            return Err(err);
+        }
+
+        // The instance was just created, so it can't contain parent
+        man.reparent(root.clone(), parent).unwrap();
+        Ok(root)
+    }
+}
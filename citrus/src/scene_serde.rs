@@ -1,5 +1,6 @@
-use std::{any::{Any, TypeId}, collections::HashMap, fmt::Debug, rc::Rc};
+use std::{any::{Any, TypeId}, collections::{HashMap, HashSet}, fmt::Debug, rc::Rc};
 use serde::*;
+use serde::de::DeserializeSeed;
 use uuid::Uuid;
 use std::fmt;
 
@@ -7,10 +8,16 @@ use crate::element::*;
 use crate::entity::*;
 use crate::deserialize_context::*;
 
+// Version written in the header of serialized scenes
+// Headerless scenes (a bare array of entities with i64 ids) are read as version 0
+pub const SCENE_FORMAT_VERSION: u32 = 1;
+
 #[derive(Debug)]
 pub enum SceneSerdeError {
     CycleError(String),
     MissingElementError(String),
+    VersionError(String),
+    PatchError(String),
     SerdeError(serde_json::Error)
 }
 
@@ -19,6 +26,8 @@ impl fmt::Display for SceneSerdeError {
         match self {
             SceneSerdeError::CycleError(info) => write!(f, "{}", info),
             SceneSerdeError::MissingElementError(info) => write!(f, "{}", info),
+            SceneSerdeError::VersionError(info) => write!(f, "{}", info),
+            SceneSerdeError::PatchError(info) => write!(f, "{}", info),
             SceneSerdeError::SerdeError(err) => write!(f, "{}", err)
         }
     }
@@ -28,7 +37,8 @@ impl fmt::Display for SceneSerdeError {
 pub struct CreatorEntry {
     pub creator: Rc<Box<dyn Fn(EntAddr) -> EleAddrErased>>,
     pub name: String,
-    pub id: TypeId
+    pub id: TypeId,
+    pub multi_instance: bool
 }
 
 pub struct SceneDeserResult {
@@ -36,6 +46,64 @@ pub struct SceneDeserResult {
     pub errors: Vec<SceneSerdeError>
 }
 
+// Serialized forms of entities and elements within a scene
+// active, enabled, tags and layers are only written when they differ from what new entities and elements start with
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct SceneEleObj {
+    pub name: String,
+    pub payload: serde_json::Value,
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    pub enabled: bool
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct SceneEntObj {
+    pub name: String,
+    pub parent_payload: serde_json::Value, // This is a serialized form of EntAddr
+    pub id: SerializedEntId,
+    pub eles: Vec<SceneEleObj>,
+    #[serde(default = "default_true")]
+    pub active: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default = "default_layers")]
+    pub layers: u32
+}
+
+pub(crate) fn default_true() -> bool {
+    true
+}
+pub(crate) fn is_true(val: &bool) -> bool {
+    *val
+}
+pub(crate) fn default_layers() -> u32 {
+    DEFAULT_LAYERS
+}
+pub(crate) fn is_default_layers(layers: &u32) -> bool {
+    *layers == DEFAULT_LAYERS
+}
+
+// Reads the entities of a scene in either the versioned or the headerless legacy format
+pub(crate) fn parse_scene(content: serde_json::Value) -> Result<Vec<SceneEntObj>, SceneSerdeError> {
+    #[derive(Deserialize)]
+    struct SceneObj {
+        version: u32,
+        entities: Vec<SceneEntObj>
+    }
+
+    // Scenes written before the versioned format are a bare array of entities
+    match content.is_array() {
+        true => serde_json::from_value::<Vec<SceneEntObj>>(content).map_err(SceneSerdeError::SerdeError),
+        false => {
+            let scene = serde_json::from_value::<SceneObj>(content).map_err(SceneSerdeError::SerdeError)?;
+            if scene.version > SCENE_FORMAT_VERSION {
+                return Err(SceneSerdeError::VersionError(format!("Scene format version {} is newer than the supported version {}", scene.version, SCENE_FORMAT_VERSION)));
+            }
+            Ok(scene.entities)
+        }
+    }
+}
+
 pub struct SceneSerde {
     creator_map: HashMap<TypeId, CreatorEntry>
 }
@@ -62,7 +130,8 @@ impl SceneSerde {
                 }
             })),
             name: name.into(),
-            id: std::any::TypeId::of::<T>()
+            id: std::any::TypeId::of::<T>(),
+            multi_instance: T::multi_instance()
         });
     }
     
@@ -75,7 +144,7 @@ impl SceneSerde {
         {
             let cloned = ent.clone();
             let mut ent_ref = cloned.get_ref_mut().unwrap();
-            assert!(!ent_ref.query_element_addr_by_id(&entry.id).valid());
+            assert!(entry.multi_instance || !ent_ref.query_element_addr_by_id(&entry.id).valid());
         }
 
         let erased = (entry.creator)(ent);
@@ -87,12 +156,6 @@ impl SceneSerde {
 
     // Returns a Some(value) if ele is valid, otherwise returns None
     pub fn serialize_element(&mut self, ele: &EleAddrErased) -> Option<serde_json::Value> {
-        #[derive(Serialize)]
-        struct ElementObj {
-            name: String,
-            payload: serde_json::Value
-        }
-
         if !ele.valid() {
             return None;
         }
@@ -101,49 +164,68 @@ impl SceneSerde {
 
         let payload = ele.get_ref().unwrap().ecs_serialize();
 
-        Some(serde_json::to_value(ElementObj {
+        Some(serde_json::to_value(SceneEleObj {
             name: creator.name,
-            payload
+            payload,
+            enabled: ele.is_enabled()
         }).unwrap())
     }
     pub fn deserialize_scene(&mut self, man: &mut Manager, content: serde_json::Value) -> Result<SceneDeserResult, SceneSerdeError> {
-        #[derive(Deserialize, Clone)]
-        struct EleObj {
-            name: String,
-            payload: serde_json::Value
+        let ent_objs = parse_scene(content)?;
+        self.deserialize_ent_objs(man, ent_objs, false)
+    }
+    // With fresh_ids every entity gets a new id, and ids of entities outside of ent_objs
+    // keep referring to the live entities of man instead of deserializing as invalid
+    pub(crate) fn deserialize_ent_objs(&mut self, man: &mut Manager, ent_objs: Vec<SceneEntObj>, fresh_ids: bool) -> Result<SceneDeserResult, SceneSerdeError> {
+        struct EntDeserializeState {
+            payload: SceneEntObj,
+            addr: EntAddr
         }
 
-        #[derive(Deserialize, Clone)]
-        struct EntObj {
-            name: String,
-            parent_payload: serde_json::Value, // This is a serialized form of EntAddr
-            id: i64,
-            eles: Vec<EleObj>
-        }
+        let mut context = DeserializeContext::new();
 
-        struct EntDeserializeState {
-            payload: EntObj,
-            addr: EntAddr
+        let ids = ent_objs.iter()
+        .map(|payload| payload.id.to_id())
+        .collect::<Result<Vec<Uuid>, String>>()
+        .map_err(|er| SceneSerdeError::SerdeError(serde::de::Error::custom(er)))?;
+
+        let mut seen = HashSet::new();
+        if let Some(dup) = ids.iter().find(|id| !seen.insert(**id)) {
+            return Err(SceneSerdeError::SerdeError(serde::de::Error::custom(format!("Entity id {} appears more than once in the scene", dup))));
         }
-        
-        begin_deserialize();
 
         // Deserialize all entity data, create the actual entities, and associate the original data with the entities
         let ent_states: Vec<EntDeserializeState> =
-        serde_json::from_value::<Vec<EntObj>>(content)
-        .map_err(|er| SceneSerdeError::SerdeError(er))?
+        ent_objs
         .into_iter()
-        .map(|payload| {
-            let addr = set_mapping(Uuid::from_u128(payload.id as u128), payload.name.clone(), man);
+        .zip(ids.iter())
+        .map(|(payload, id)| {
+            let addr = match fresh_ids {
+                true => context.set_mapping_fresh(*id, payload.name.clone(), man),
+                false => context.set_mapping(*id, payload.name.clone(), man)
+            };
+            {
+                let mut ent = addr.get_ref_mut().unwrap();
+                ent.set_active(payload.active);
+                ent.set_tags(payload.tags.clone());
+                ent.set_layers(payload.layers);
+            }
             EntDeserializeState { payload, addr }
         })
         .collect();
+
+        if fresh_ids {
+            ent_states.iter().for_each(|state| {
+                context.map_live_ids(man, &state.payload.parent_payload);
+                state.payload.eles.iter().for_each(|ele_obj| context.map_live_ids(man, &ele_obj.payload));
+            });
+        }
         
         let mut reparent_failures = Vec::<String>::new();
         // All entities have been created; we can now assign parent/child relations
         ent_states.iter().for_each(|state| {
-            let parent_addr = serde_json::from_value::<EntAddr>(state.payload.parent_payload.clone()).unwrap();
-            let child_addr = map_id(Uuid::from_u128(state.payload.id as u128));
+            let parent_addr = EntAddrSeed(&context).deserialize(state.payload.parent_payload.clone()).unwrap();
+            let child_addr = state.addr.clone();
             if let Err(_er) = man.reparent(child_addr.clone(), parent_addr.clone()) {
                 let child_ent = child_addr.get_ref().unwrap();
                 let parent_ent = parent_addr.get_ref().unwrap();
@@ -157,7 +239,6 @@ impl SceneSerde {
                 man.destroy_entity(state.addr);
             });
             man.resolve();
-            end_deserialize();
             return Err(SceneSerdeError::CycleError(reparent_failures.join("\n")));
         }
 
@@ -173,9 +254,12 @@ impl SceneSerde {
             pair.payload.eles.iter().map(|ele_obj| {
                 self
                 .deserialize_empty_into(pair.addr.clone(), ele_obj.name.clone())
-                .map(|ele| EleAddrDeserializeState {
-                    ele,
-                    payload: ele_obj.payload.clone()
+                .map(|ele| {
+                    ele.set_enabled(ele_obj.enabled);
+                    EleAddrDeserializeState {
+                        ele,
+                        payload: ele_obj.payload.clone()
+                    }
                 })
             })
             .collect::<Vec<Result<EleAddrDeserializeState, SceneSerdeError>>>()
@@ -189,13 +273,23 @@ impl SceneSerde {
         .filter(|attempt| attempt.is_ok())
         .map(|attempt| attempt.as_ref().ok().unwrap())
         .map(|state| {
-            state.ele.clone().get_ref_mut().unwrap().ecs_deserialize(state.payload.clone())
+            context.scope(|| state.ele.clone().get_ref_mut().unwrap().ecs_deserialize(state.payload.clone()))
         })
         .filter(|state_attempt| state_attempt.is_err())
         .map(|state_attempt| SceneSerdeError::SerdeError(state_attempt.err().unwrap()))
         .collect();
 
-        end_deserialize();
+        // Lifecycle hooks run once all of the scene's data is in place
+        man.notify_added();
+        deser_attempts
+        .iter()
+        .filter_map(|attempt| attempt.as_ref().ok())
+        .for_each(|state| {
+            let owner = state.ele.get_owner();
+            if let Some(mut ele) = state.ele.clone().get_ref_mut() {
+                ele.on_deserialized(man, owner);
+            }
+        });
 
         let errors =
         deser_attempts
@@ -215,8 +309,20 @@ impl SceneSerde {
         struct EntObj {
             name: String,
             parent_payload: serde_json::Value,
-            id: i64,
-            eles: Vec<serde_json::Value>
+            id: Option<SerializedEntId>,
+            eles: Vec<serde_json::Value>,
+            #[serde(skip_serializing_if = "is_true")]
+            active: bool,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            tags: Vec<String>,
+            #[serde(skip_serializing_if = "is_default_layers")]
+            layers: u32
+        }
+
+        #[derive(Serialize)]
+        struct SceneObj {
+            version: u32,
+            entities: Vec<EntObj>
         }
 
         let ent_objs: Vec<EntObj>
@@ -237,15 +343,21 @@ impl SceneSerde {
                     name: ea.get_ref().unwrap().name.clone(),
                     parent_payload: serde_json::to_value(ea.get_ref().unwrap().get_parent()).unwrap(),
                     id: match ea.get_ref() {
-                        None => 0i64,
-                        Some(e) => e.get_id().as_u128() as i64
+                        None => None,
+                        Some(e) => SerializedEntId::from_id(e.get_id())
                     },
-                    eles
+                    eles,
+                    active: ea.get_ref().unwrap().is_active_self(),
+                    tags: ea.get_ref().unwrap().get_tags().to_vec(),
+                    layers: ea.get_ref().unwrap().get_layers()
                 }
             })
             .collect();
         
-        serde_json::to_value(ent_objs).unwrap()
+        serde_json::to_value(SceneObj {
+            version: SCENE_FORMAT_VERSION,
+            entities: ent_objs
+        }).unwrap()
     }   
 
     // Utility functions
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+
+use crate::entity::*;
+use crate::sync::*;
+
+// Stages run by Manager::update, in this order
+// Element::update runs at the start of Update, before the Update systems, and Element::fixed_update just before it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Stage {
+    PreUpdate,
+    Update,
+    PostUpdate,
+    Render
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::PreUpdate, Stage::Update, Stage::PostUpdate, Stage::Render];
+}
+
+// Logic that runs over the whole Manager once per stage, usually a free function over queries:
+//   fn gravity(man: &mut Manager) { man.query_each::<Velocity, _>(|_, mut vel| vel.y -= 9.8); }
+// With the sync feature systems have to be Send + Sync, so the Manager holding them is
+pub trait System : MaybeSync + 'static {
+    fn run(&mut self, man: &mut Manager);
+}
+
+impl<F: FnMut(&mut Manager) + MaybeSync + 'static> System for F {
+    fn run(&mut self, man: &mut Manager) {
+        self(man)
+    }
+}
+
+// system is taken out of the entry while it runs
+pub struct SystemEntry {
+    name: String,
+    stage: Stage,
+    system: Option<Box<dyn System>>,
+    before: Vec<String>,
+    after: Vec<String>
+}
+
+impl SystemEntry {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+    // Ordering constraints only apply between systems of the same stage; names with no such system are ignored
+    pub fn before(&mut self, name: &str) -> &mut Self {
+        self.before.push(name.to_string());
+        self
+    }
+    pub fn after(&mut self, name: &str) -> &mut Self {
+        self.after.push(name.to_string());
+        self
+    }
+}
+
+enum ScheduleEdit {
+    Add(SystemEntry),
+    Remove(String)
+}
+
+// The systems registered on a Manager
+// Within a stage systems run in the order they were added, except where before/after says otherwise
+// Systems added or removed while a stage runs (e.g. by a system) are queued in pending until it's done
+// cycles holds the ordering cycles the last run of each stage ran into
+#[derive(Default)]
+pub struct Schedule {
+    systems: Vec<SystemEntry>,
+    pending: Vec<ScheduleEdit>,
+    running: usize,
+    cycles: HashMap<Stage, String>
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Fails if a system with that name already exists
+    // While a stage runs the system is added once the stage is done, but get_system already finds it
+    pub fn add_system(&mut self, stage: Stage, name: &str, system: impl System) -> Result<&mut SystemEntry, String> {
+        if self.contains(name) {
+            return Err(format!("System \"{}\" already exists", name));
+        }
+        let entry = SystemEntry {
+            name: name.to_string(),
+            stage,
+            system: Some(Box::new(system)),
+            before: Vec::new(),
+            after: Vec::new()
+        };
+        if self.running > 0 {
+            self.pending.push(ScheduleEdit::Add(entry));
+            return match self.pending.last_mut() {
+                Some(ScheduleEdit::Add(entry)) => Ok(entry),
+                _ => unreachable!()
+            };
+        }
+        self.systems.push(entry);
+        Ok(self.systems.last_mut().unwrap())
+    }
+    // While a stage runs the system is removed once the stage is done, so it still runs in it if it hasn't yet
+    pub fn remove_system(&mut self, name: &str) -> bool {
+        if !self.contains(name) {
+            return false;
+        }
+        match self.running > 0 {
+            true => self.pending.push(ScheduleEdit::Remove(name.to_string())),
+            false => self.systems.retain(|entry| entry.name != name)
+        }
+        true
+    }
+    pub fn get_system(&mut self, name: &str) -> Option<&mut SystemEntry> {
+        if !self.contains(name) {
+            return None;
+        }
+        let pending = self.pending.iter_mut().rev().find_map(|edit| match edit {
+            ScheduleEdit::Add(entry) if entry.name == name => Some(entry),
+            _ => None
+        });
+        match pending {
+            Some(entry) => Some(entry),
+            None => self.systems.iter_mut().find(|entry| entry.name == name)
+        }
+    }
+    pub fn systems(&self) -> &[SystemEntry] {
+        &self.systems
+    }
+    // Why the last run of the stage ran some of its systems out of order, if it did
+    pub fn cycle(&self, stage: Stage) -> Option<&str> {
+        self.cycles.get(&stage).map(|cycle| cycle.as_str())
+    }
+
+    // Whether there is a system with the name once the pending edits are applied
+    fn contains(&self, name: &str) -> bool {
+        self.pending.iter().fold(self.systems.iter().any(|entry| entry.name == name), |exists, edit| match edit {
+            ScheduleEdit::Add(entry) if entry.name == name => true,
+            ScheduleEdit::Remove(removed) if removed == name => false,
+            _ => exists
+        })
+    }
+
+    // Indices into systems in the order they run, and the names of the systems left unordered by a cycle, if any
+    // Those run last, in the order they were added
+    fn order(&self, stage: Stage) -> (Vec<usize>, Option<String>) {
+        let in_stage = (0..self.systems.len()).filter(|i| self.systems[*i].stage == stage).collect::<Vec<usize>>();
+        let find = |name: &String| in_stage.iter().copied().find(|i| self.systems[*i].name == *name);
+
+        // edges[i] holds the systems that have to run after system i
+        let mut edges = vec![Vec::new(); self.systems.len()];
+        let mut incoming = vec![0_usize; self.systems.len()];
+        for i in in_stage.iter().copied() {
+            let entry = &self.systems[i];
+            let befores = entry.before.iter().filter_map(find).map(|other| (i, other));
+            let afters = entry.after.iter().filter_map(find).map(|other| (other, i));
+            for (first, second) in befores.chain(afters) {
+                edges[first].push(second);
+                incoming[second] += 1;
+            }
+        }
+
+        // Always picking the earliest added system that is ready keeps the order stable
+        let mut res = Vec::new();
+        let mut ready = in_stage.iter().copied().filter(|i| incoming[*i] == 0).collect::<Vec<usize>>();
+        while !ready.is_empty() {
+            let next = ready.remove(0);
+            res.push(next);
+            for other in edges[next].iter().copied() {
+                incoming[other] -= 1;
+                if incoming[other] == 0 {
+                    let at = ready.partition_point(|i| *i < other);
+                    ready.insert(at, other);
+                }
+            }
+        }
+
+        if res.len() == in_stage.len() {
+            return (res, None);
+        }
+        let unordered = in_stage.into_iter().filter(|i| !res.contains(i)).collect::<Vec<usize>>();
+        let names = unordered.iter().map(|i| self.systems[*i].name.clone()).collect::<Vec<String>>().join(", ");
+        res.extend(unordered);
+        (res, Some(names))
+    }
+
+    // Names of the systems of a stage in the order they run; fails if their ordering constraints form a cycle
+    pub fn stage_order(&self, stage: Stage) -> Result<Vec<String>, String> {
+        match self.order(stage) {
+            (order, None) => Ok(order.into_iter().map(|i| self.systems[i].name.clone()).collect()),
+            (_, Some(names)) => Err(cycle_error(names))
+        }
+    }
+
+    // Queues edits until the matching end_stage and returns the order to run the stage's systems in
+    pub(crate) fn begin_stage(&mut self, stage: Stage) -> Vec<usize> {
+        self.running += 1;
+        let (order, cycle) = self.order(stage);
+        match cycle {
+            Some(names) => { self.cycles.insert(stage, cycle_error(names)); },
+            None => { self.cycles.remove(&stage); }
+        }
+        order
+    }
+    // None if the system is already running (a stage run from inside a system)
+    pub(crate) fn take_system(&mut self, index: usize) -> Option<Box<dyn System>> {
+        self.systems[index].system.take()
+    }
+    pub(crate) fn return_system(&mut self, index: usize, system: Box<dyn System>) {
+        self.systems[index].system = Some(system);
+    }
+    pub(crate) fn end_stage(&mut self) {
+        self.running -= 1;
+        if self.running > 0 {
+            return;
+        }
+        for edit in std::mem::take(&mut self.pending).into_iter() {
+            match edit {
+                ScheduleEdit::Add(entry) => self.systems.push(entry),
+                ScheduleEdit::Remove(name) => self.systems.retain(|entry| entry.name != name)
+            }
+        }
+    }
+}
+
+fn cycle_error(names: String) -> String {
+    format!("Systems {} have cyclic ordering constraints", names)
+}
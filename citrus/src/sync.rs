@@ -0,0 +1,76 @@
+// The shared state behind addresses and references is Rc/Cell based by default
+// and Arc/atomic/Mutex based with the sync feature, so it can be used from several threads at once
+
+#[cfg(not(feature = "sync"))]
+pub(crate) use std::rc::{Rc as Shared, Weak as WeakShared};
+#[cfg(feature = "sync")]
+pub(crate) use std::sync::{Arc as Shared, Weak as WeakShared};
+
+// Send + Sync with the sync feature, implemented by everything otherwise
+#[cfg(not(feature = "sync"))]
+pub trait MaybeSync { }
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> MaybeSync for T { }
+#[cfg(feature = "sync")]
+pub trait MaybeSync : Send + Sync { }
+#[cfg(feature = "sync")]
+impl<T: ?Sized + Send + Sync> MaybeSync for T { }
+
+// A Cell, or with the sync feature a Mutex, for small Copy values
+pub(crate) struct SharedCell<T: Copy> {
+    #[cfg(not(feature = "sync"))]
+    val: std::cell::Cell<T>,
+    #[cfg(feature = "sync")]
+    val: std::sync::Mutex<T>
+}
+
+impl<T: Copy> SharedCell<T> {
+    pub(crate) fn new(val: T) -> Self {
+        Self { val: val.into() }
+    }
+    #[cfg(not(feature = "sync"))]
+    pub(crate) fn get(&self) -> T {
+        self.val.get()
+    }
+    #[cfg(not(feature = "sync"))]
+    pub(crate) fn set(&self, val: T) {
+        self.val.set(val)
+    }
+    #[cfg(feature = "sync")]
+    pub(crate) fn get(&self) -> T {
+        *self.val.lock().unwrap()
+    }
+    #[cfg(feature = "sync")]
+    pub(crate) fn set(&self, val: T) {
+        *self.val.lock().unwrap() = val
+    }
+}
+
+// A RefCell, or with the sync feature a Mutex, for state a Manager shares with its entities
+// Only ever borrowed exclusively, so code that would deadlock on the Mutex panics on the RefCell instead
+pub(crate) struct SharedLock<T> {
+    #[cfg(not(feature = "sync"))]
+    val: std::cell::RefCell<T>,
+    #[cfg(feature = "sync")]
+    val: std::sync::Mutex<T>
+}
+
+impl<T> SharedLock<T> {
+    pub(crate) fn new(val: T) -> Self {
+        Self { val: val.into() }
+    }
+    #[cfg(not(feature = "sync"))]
+    pub(crate) fn lock(&self) -> std::cell::RefMut<'_, T> {
+        self.val.borrow_mut()
+    }
+    #[cfg(feature = "sync")]
+    pub(crate) fn lock(&self) -> std::sync::MutexGuard<'_, T> {
+        self.val.lock().unwrap()
+    }
+}
+
+// The type erased values a Manager holds (events, resources); Send + Sync with the sync feature
+#[cfg(not(feature = "sync"))]
+pub(crate) type AnyShared = dyn std::any::Any;
+#[cfg(feature = "sync")]
+pub(crate) type AnyShared = dyn std::any::Any + Send + Sync;
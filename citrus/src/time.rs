@@ -0,0 +1,85 @@
+// Frame timing, kept up to date by Manager::update as a resource: man.resource::<Time>()
+// With a fixed timestep set, every update runs Element::fixed_update once per whole step of accumulated time;
+// alpha is how far the leftover time got into the next step, for interpolating between the last two fixed states
+pub struct Time {
+    delta: f32,
+    elapsed: f64,
+    frame: u64,
+    fixed_delta: Option<f32>,
+    max_fixed_steps: u32,
+    accumulator: f32,
+    alpha: f32
+}
+
+impl Default for Time {
+    fn default() -> Self {
+        Self {
+            delta: 0.0,
+            elapsed: 0.0,
+            frame: 0,
+            fixed_delta: None,
+            max_fixed_steps: 8,
+            accumulator: 0.0,
+            alpha: 0.0
+        }
+    }
+}
+
+impl Time {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Seconds passed to the current Manager::update
+    pub fn delta(&self) -> f32 {
+        self.delta
+    }
+    // Seconds passed to every Manager::update so far, including the current one
+    pub fn elapsed(&self) -> f64 {
+        self.elapsed
+    }
+    // Number of Manager::update calls so far, including the current one
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+    pub fn fixed_delta(&self) -> Option<f32> {
+        self.fixed_delta
+    }
+    // Between 0 and 1; always 0 without a fixed timestep
+    pub fn alpha(&self) -> f32 {
+        self.alpha
+    }
+
+    // None turns fixed updates off; panics if step isn't positive
+    pub fn set_fixed_delta(&mut self, step: Option<f32>) {
+        assert!(step.is_none_or(|step| step > 0.0), "Fixed timestep has to be positive");
+        self.fixed_delta = step;
+        self.accumulator = 0.0;
+        self.alpha = 0.0;
+    }
+    // Caps the fixed updates per frame so a slow frame can't snowball; the time beyond the cap is dropped
+    pub fn set_max_fixed_steps(&mut self, max: u32) {
+        self.max_fixed_steps = max;
+    }
+
+    // Starts a frame of dt seconds and returns the number of fixed updates to run in it
+    pub(crate) fn advance(&mut self, dt: f32) -> u32 {
+        self.delta = dt;
+        self.elapsed += dt as f64;
+        self.frame += 1;
+
+        let step = match self.fixed_delta {
+            Some(step) => step,
+            None => return 0
+        };
+        self.accumulator += dt;
+        let mut steps = 0;
+        while self.accumulator >= step && steps < self.max_fixed_steps {
+            self.accumulator -= step;
+            steps += 1;
+        }
+        self.accumulator %= step;
+        self.alpha = self.accumulator / step;
+        steps
+    }
+}